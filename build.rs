@@ -0,0 +1,17 @@
+use std::process::Command;
+
+// Embeds the commit being built as GIT_SHA, taken from the environment when there is no .git, e.g. in the Docker build
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
+    let sha = std::env::var("GIT_SHA").ok()
+        .filter(|sha| !sha.is_empty())
+        .or_else(|| {
+            let output = Command::new("git").args(["rev-parse", "--short", "HEAD"]).output().ok()?;
+            output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .unwrap_or_else(|| String::from("unknown"));
+    println!("cargo:rustc-env=GIT_SHA={}", sha);
+}
@@ -0,0 +1,31 @@
+use k8s_openapi::api::authorization::v1::{ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec};
+use kube::api::PostParams;
+use kube::{Api, Client};
+use tracing::warn;
+
+// Watching Secrets only speeds up reacting to their changes, so without the permission the operator falls back to the
+// periodic requeue instead of retrying a watch that can never succeed
+pub async fn can_watch_secrets(client: &Client, namespace: Option<&str>) -> bool {
+    let review = SelfSubjectAccessReview {
+        spec: SelfSubjectAccessReviewSpec {
+            resource_attributes: Some(ResourceAttributes {
+                namespace: namespace.map(String::from),
+                verb: Some(String::from("watch")),
+                resource: Some(String::from("secrets")),
+                ..ResourceAttributes::default()
+            }),
+            ..SelfSubjectAccessReviewSpec::default()
+        },
+        ..SelfSubjectAccessReview::default()
+    };
+
+    let api = Api::<SelfSubjectAccessReview>::all(client.clone());
+    match api.create(&PostParams::default(), &review).await {
+        Ok(review) => review.status.is_some_and(|status| status.allowed),
+        Err(e) => {
+            // Not being able to ask doesn't mean the watch would fail, so it is attempted anyway
+            warn!("Failed to check whether Secrets can be watched, assuming they can: {:?}", e);
+            true
+        }
+    }
+}
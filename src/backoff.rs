@@ -0,0 +1,27 @@
+use crate::index::ObjectKey;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+// Consecutive reconcile failures per object, so a persistently broken one is retried less and less often
+#[derive(Clone, Default)]
+pub struct FailureBackoff {
+    failures: Arc<Mutex<HashMap<ObjectKey, u32>>>,
+}
+
+impl FailureBackoff {
+    // Counts another failure and returns how long to wait, doubling from `base` up to 15 minutes
+    pub fn next_delay(&self, object: ObjectKey, base: Duration) -> Duration {
+        let mut failures = self.failures.lock().unwrap();
+        let count = failures.entry(object).or_default();
+        let delay = base.saturating_mul(2u32.saturating_pow(*count)).min(MAX_BACKOFF);
+        *count = count.saturating_add(1);
+        delay
+    }
+
+    pub fn reset(&self, object: &ObjectKey) {
+        self.failures.lock().unwrap().remove(object);
+    }
+}
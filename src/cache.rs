@@ -0,0 +1,46 @@
+use crate::index::SecretKey;
+use k8s_openapi::api::core::v1::Secret;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const MAX_ENTRIES: usize = 1024;
+
+// Recently fetched Secrets, so a burst of reconciles referencing the same Secret, e.g. during the initial sync, shares
+// one API call. Secret watch events evict entries right away, the TTL only bounds staleness without a watch
+#[derive(Clone)]
+pub struct SecretCache {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<SecretKey, (Instant, Secret)>>>,
+}
+
+impl SecretCache {
+    pub fn new(ttl: Duration) -> SecretCache {
+        SecretCache { ttl, entries: Default::default() }
+    }
+
+    pub fn get(&self, secret: &SecretKey) -> Option<Secret> {
+        let entries = self.entries.lock().unwrap();
+        let (fetched_at, cached) = entries.get(secret)?;
+        (fetched_at.elapsed() < self.ttl).then(|| cached.clone())
+    }
+
+    pub fn insert(&self, secret: SecretKey, value: Secret) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (fetched_at, _)| fetched_at.elapsed() < self.ttl);
+            if entries.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        entries.insert(secret, (Instant::now(), value));
+    }
+
+    pub fn invalidate(&self, secret: &SecretKey) {
+        self.entries.lock().unwrap().remove(secret);
+    }
+}
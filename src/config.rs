@@ -0,0 +1,424 @@
+use anyhow::{bail, Context, Result};
+use kube::core::{Selector, SelectorExt};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{env, fs};
+use tracing::warn;
+use crate::selector;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub annotation_domain: String,
+    pub annotations: AnnotationKeys,
+    pub finalizer: String,
+    pub placeholder_prefix: String,
+    pub placeholder_suffix: String,
+    pub placeholder_style: PlaceholderStyle,
+    pub metrics_port: u16,
+    pub mode: Mode,
+    pub webhook_port: u16,
+    pub tls_cert_file: PathBuf,
+    pub tls_key_file: PathBuf,
+    pub kubeconfig_context: Option<String>,
+    pub in_cluster: bool,
+    pub concurrency: u16,
+    pub leader_election: bool,
+    pub lease_name: String,
+    pub lease_namespace: String,
+    pub watch_namespaces: Option<Vec<String>>,
+    pub label_selector: Option<Selector>,
+    pub ingress_class: Option<String>,
+    pub secret_name_label: Option<String>,
+    pub allowed_source_namespaces: Vec<String>,
+    pub skip_annotation_prefixes: Vec<String>,
+    pub protected_annotations: Vec<String>,
+    pub skip_last_applied: bool,
+    pub shutdown_timeout: Duration,
+    pub requeue_interval: Duration,
+    pub error_requeue_interval: Duration,
+    pub missing_secret_requeue_interval: Duration,
+    pub forbidden_requeue_interval: Duration,
+    pub transient_error_requeue_interval: Duration,
+    pub permanent_error_requeue_interval: Duration,
+    pub debounce: Duration,
+    pub secret_cache_ttl: Duration,
+    pub full_resync_interval: Option<Duration>,
+    pub dry_run: bool,
+    pub paused: bool,
+    pub pause_file: Option<PathBuf>,
+    pub field_manager: String,
+    pub patch_strategy: PatchStrategy,
+    pub case_insensitive_keys: bool,
+    pub substitution_passes: u32,
+    pub max_value_length: Option<usize>,
+    pub requeue_jitter_percent: u32,
+    pub skip_empty: bool,
+    pub trim_values: bool,
+    pub compress_state: bool,
+    pub secret_key_precedence: SecretKeyPrecedence,
+    pub annotation_templates: bool,
+}
+
+// Which of a Secret's `data` and `stringData` wins when both hold the same key. The API server folds `stringData`
+// into `data` on write, so both only show up together on objects that never went through it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SecretKeyPrecedence {
+    DataFirst,
+    StringDataFirst,
+}
+
+// Which placeholder syntax is recognized: the configured prefix and suffix (`$KEY$` by default), shell-like `${KEY}`,
+// or both
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlaceholderStyle {
+    DollarPair,
+    Brace,
+    Both,
+}
+
+// Either watching resources and patching them after the fact, or substituting them inline as a mutating admission
+// webhook before they are stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Controller,
+    Webhook,
+}
+
+// How changed annotations are written: a merge patch of the changed keys, or a JSON patch adding each of them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PatchStrategy {
+    Merge,
+    Json,
+}
+
+// Annotation keys owned by this operator, all living under the configured domain
+#[derive(Clone, Debug)]
+pub struct AnnotationKeys {
+    pub secret_name: String,
+    pub config_map_name: String,
+    pub annotation_keys: String,
+    pub templated_keys: String,
+    pub secret_keys: String,
+    pub labels_secret_name: String,
+    pub disabled: String,
+    pub propagate: String,
+    pub resync_at: String,
+    pub requeue_seconds: String,
+    pub max_value_length: String,
+    pub state: String,
+    pub label_templates: String,
+    pub label_state: String,
+    pub spec_templates: String,
+    pub spec_state: String,
+    pub checksum: String,
+    pub dry_run_plan: String,
+}
+
+impl AnnotationKeys {
+    fn new(domain: &str) -> AnnotationKeys {
+        AnnotationKeys {
+            secret_name: format!("{}/annotationsFromSecretName", domain),
+            config_map_name: format!("{}/annotationsFromConfigMapName", domain),
+            annotation_keys: format!("{}/annotationKeys", domain),
+            templated_keys: format!("{}/templatedKeys", domain),
+            secret_keys: format!("{}/secretKeys", domain),
+            labels_secret_name: format!("{}/labelsFromSecretName", domain),
+            disabled: format!("{}/disabled", domain),
+            propagate: format!("{}/propagate", domain),
+            resync_at: format!("{}/resyncAt", domain),
+            requeue_seconds: format!("{}/requeueSeconds", domain),
+            max_value_length: format!("{}/maxValueLength", domain),
+            state: format!("{}/annotationsFromSecretState", domain),
+            label_templates: format!("{}/labelTemplates", domain),
+            label_state: format!("{}/labelsFromSecretState", domain),
+            spec_templates: format!("{}/specTemplates", domain),
+            spec_state: format!("{}/specTemplatesState", domain),
+            checksum: format!("{}/secretChecksum", domain),
+            dry_run_plan: format!("{}/dryRunPlan", domain),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config> {
+        let annotation_domain = env::var("ANNOTATION_DOMAIN").unwrap_or_else(|_| String::from("kirillorlov.pro"));
+        if annotation_domain.is_empty() {
+            bail!("ANNOTATION_DOMAIN must not be empty");
+        }
+
+        let placeholder_prefix = env::var("PLACEHOLDER_PREFIX").unwrap_or_else(|_| String::from("$"));
+        let placeholder_suffix = env::var("PLACEHOLDER_SUFFIX").unwrap_or_else(|_| String::from("$"));
+
+        if placeholder_prefix.is_empty() {
+            bail!("PLACEHOLDER_PREFIX must not be empty");
+        }
+        if placeholder_suffix.is_empty() {
+            bail!("PLACEHOLDER_SUFFIX must not be empty");
+        }
+
+        let placeholder_style = match env::var("PLACEHOLDER_STYLE").as_deref() {
+            Ok("dollar-pair") | Err(_) => PlaceholderStyle::DollarPair,
+            Ok("brace") => PlaceholderStyle::Brace,
+            Ok("both") => PlaceholderStyle::Both,
+            Ok(value) => bail!("Invalid PLACEHOLDER_STYLE {:?}, expected dollar-pair, brace or both", value),
+        };
+
+        let metrics_port = match env::var("METRICS_PORT") {
+            Ok(port) => port.parse().with_context(|| format!("Invalid METRICS_PORT {:?}", port))?,
+            Err(_) => 8080,
+        };
+
+        let mode = match env::var("MODE").as_deref() {
+            Ok("controller") | Err(_) => Mode::Controller,
+            Ok("webhook") => Mode::Webhook,
+            Ok(value) => bail!("Invalid MODE {:?}, expected controller or webhook", value),
+        };
+
+        let webhook_port = match env::var("WEBHOOK_PORT") {
+            Ok(port) => port.parse().with_context(|| format!("Invalid WEBHOOK_PORT {:?}", port))?,
+            Err(_) => 8443,
+        };
+
+        let kubeconfig_context = env::var("KUBECONFIG_CONTEXT").ok().filter(|context| !context.is_empty());
+        let in_cluster = flag("IN_CLUSTER");
+        if in_cluster && kubeconfig_context.is_some() {
+            bail!("IN_CLUSTER and KUBECONFIG_CONTEXT are mutually exclusive");
+        }
+
+        // Reconciles running at once across all kinds and namespaces together, 0 lifts the limit entirely
+        let concurrency = match env::var("CONCURRENCY") {
+            Ok(concurrency) => concurrency.parse().with_context(|| format!("Invalid CONCURRENCY {:?}", concurrency))?,
+            Err(_) => 5,
+        };
+
+        let leader_election = flag("LEADER_ELECTION");
+        let lease_name = env::var("LEASE_NAME").unwrap_or_else(|_| String::from("annotations-from-secret-applier"));
+        let lease_namespace = env::var("LEASE_NAMESPACE").unwrap_or_else(|_| operator_namespace());
+
+        let watch_namespaces = env::var("WATCH_NAMESPACES").ok()
+            .map(|namespaces| split_list(&namespaces))
+            .filter(|namespaces| !namespaces.is_empty());
+
+        // One operator per namespace, so a Role is enough instead of a ClusterRole
+        let watch_namespaces = match (flag("SINGLE_NAMESPACE"), watch_namespaces) {
+            (true, Some(_)) => bail!("SINGLE_NAMESPACE and WATCH_NAMESPACES are mutually exclusive"),
+            (true, None) => Some(vec![operator_namespace()]),
+            (false, watch_namespaces) => watch_namespaces,
+        };
+
+        let label_selector = match env::var("LABEL_SELECTOR") {
+            Ok(value) if !value.trim().is_empty() => match selector::parse(&value) {
+                Some(label_selector) => Some(label_selector),
+                None => bail!("Invalid LABEL_SELECTOR {:?}", value),
+            },
+            _ => None,
+        };
+
+        let secret_key_precedence = match env::var("SECRET_KEY_PRECEDENCE").as_deref() {
+            Ok("data-first") => SecretKeyPrecedence::DataFirst,
+            Ok("string-data-first") | Err(_) => SecretKeyPrecedence::StringDataFirst,
+            Ok(value) => bail!("Invalid SECRET_KEY_PRECEDENCE {:?}, expected data-first or string-data-first", value),
+        };
+
+        let patch_strategy = match env::var("PATCH_STRATEGY").as_deref() {
+            Ok("merge") | Err(_) => PatchStrategy::Merge,
+            Ok("json") => PatchStrategy::Json,
+            Ok(value) => bail!("Invalid PATCH_STRATEGY {:?}, expected merge or json", value),
+        };
+
+        // Placeholders inside substituted values are expanded up to this many levels deep
+        let substitution_passes = match env::var("SUBSTITUTION_PASSES") {
+            Ok(passes) => match passes.parse() {
+                Ok(passes) if passes >= 1 => passes,
+                _ => bail!("Invalid SUBSTITUTION_PASSES {:?}, expected a positive number", passes),
+            },
+            Err(_) => 1,
+        };
+
+        // Substituted annotation values longer than this many bytes are skipped instead of written
+        let max_value_length = match env::var("MAX_VALUE_LENGTH") {
+            Ok(length) => match length.parse() {
+                Ok(length) if length >= 1 => Some(length),
+                _ => bail!("Invalid MAX_VALUE_LENGTH {:?}, expected a positive number", length),
+            },
+            Err(_) => None,
+        };
+
+        let requeue_jitter_percent = match env::var("REQUEUE_JITTER_PERCENT") {
+            Ok(percent) => match percent.parse() {
+                Ok(percent) if percent <= 100 => percent,
+                _ => bail!("Invalid REQUEUE_JITTER_PERCENT {:?}, expected a number from 0 to 100", percent),
+            },
+            Err(_) => 10,
+        };
+
+        Ok(Config {
+            annotations: AnnotationKeys::new(&annotation_domain),
+            finalizer: format!("{}/cleanup", annotation_domain),
+            annotation_domain,
+            placeholder_prefix,
+            placeholder_suffix,
+            placeholder_style,
+            metrics_port,
+            mode,
+            webhook_port,
+            // Where cert-manager or a mounted kubernetes.io/tls Secret put them by default
+            tls_cert_file: env::var_os("TLS_CERT_FILE").map_or_else(|| PathBuf::from("/tls/tls.crt"), PathBuf::from),
+            tls_key_file: env::var_os("TLS_KEY_FILE").map_or_else(|| PathBuf::from("/tls/tls.key"), PathBuf::from),
+            kubeconfig_context,
+            in_cluster,
+            concurrency,
+            leader_election,
+            lease_name,
+            lease_namespace,
+            watch_namespaces,
+            label_selector,
+            ingress_class: env::var("INGRESS_CLASS").ok().filter(|ingress_class| !ingress_class.is_empty()),
+            secret_name_label: env::var("SECRET_NAME_LABEL").ok().filter(|label| !label.is_empty()),
+            allowed_source_namespaces: env::var("ALLOWED_SOURCE_NAMESPACES").map(|namespaces| split_list(&namespaces)).unwrap_or_default(),
+            skip_annotation_prefixes: env::var("SKIP_ANNOTATION_PREFIXES").map(|prefixes| split_list(&prefixes)).unwrap_or_default(),
+            protected_annotations: env::var("PROTECTED_ANNOTATIONS").map(|keys| split_list(&keys)).unwrap_or_default(),
+            skip_last_applied: env::var("SKIP_LAST_APPLIED").map_or(true, |value| value != "false"),
+            shutdown_timeout: seconds("SHUTDOWN_TIMEOUT_SECONDS", 30),
+            requeue_interval: seconds("REQUEUE_SECONDS", 300),
+            error_requeue_interval: seconds("ERROR_REQUEUE_SECONDS", 60),
+            missing_secret_requeue_interval: seconds("MISSING_SECRET_REQUEUE_SECONDS", 600),
+            forbidden_requeue_interval: seconds("FORBIDDEN_REQUEUE_SECONDS", 900),
+            transient_error_requeue_interval: seconds("TRANSIENT_ERROR_REQUEUE_SECONDS", 5),
+            permanent_error_requeue_interval: seconds("PERMANENT_ERROR_REQUEUE_SECONDS", 900),
+            secret_cache_ttl: seconds("SECRET_CACHE_TTL_SECONDS", 10),
+            // Disabled unless set, 0 disables it too
+            full_resync_interval: Some(seconds("FULL_RESYNC_SECONDS", 0)).filter(|interval| !interval.is_zero()),
+            debounce: seconds("DEBOUNCE_SECONDS", 2),
+            dry_run: flag("DRY_RUN"),
+            paused: flag("PAUSED"),
+            pause_file: env::var_os("PAUSE_FILE").map(PathBuf::from),
+            field_manager: env::var("FIELD_MANAGER").unwrap_or_else(|_| String::from("annotations-from-secret")),
+            patch_strategy,
+            case_insensitive_keys: flag("CASE_INSENSITIVE_KEYS"),
+            substitution_passes,
+            max_value_length,
+            requeue_jitter_percent,
+            skip_empty: flag("SKIP_EMPTY"),
+            trim_values: flag("TRIM_VALUES"),
+            compress_state: flag("COMPRESS_STATE"),
+            secret_key_precedence,
+            annotation_templates: flag("ANNOTATION_TEMPLATES"),
+        })
+    }
+
+    // What from_env returns with nothing set, without reading the environment, so tests don't depend on the shell
+    // running them
+    #[cfg(test)]
+    pub fn for_tests() -> Config {
+        let annotation_domain = String::from("kirillorlov.pro");
+        Config {
+            annotations: AnnotationKeys::new(&annotation_domain),
+            finalizer: format!("{}/cleanup", annotation_domain),
+            annotation_domain,
+            placeholder_prefix: String::from("$"),
+            placeholder_suffix: String::from("$"),
+            placeholder_style: PlaceholderStyle::DollarPair,
+            metrics_port: 8080,
+            mode: Mode::Controller,
+            webhook_port: 8443,
+            tls_cert_file: PathBuf::from("/tls/tls.crt"),
+            tls_key_file: PathBuf::from("/tls/tls.key"),
+            kubeconfig_context: None,
+            in_cluster: false,
+            concurrency: 5,
+            leader_election: false,
+            lease_name: String::from("annotations-from-secret-applier"),
+            lease_namespace: String::from("default"),
+            watch_namespaces: None,
+            label_selector: None,
+            ingress_class: None,
+            secret_name_label: None,
+            allowed_source_namespaces: Vec::new(),
+            skip_annotation_prefixes: Vec::new(),
+            protected_annotations: Vec::new(),
+            skip_last_applied: true,
+            shutdown_timeout: Duration::from_secs(30),
+            requeue_interval: Duration::from_secs(300),
+            error_requeue_interval: Duration::from_secs(60),
+            missing_secret_requeue_interval: Duration::from_secs(600),
+            forbidden_requeue_interval: Duration::from_secs(900),
+            transient_error_requeue_interval: Duration::from_secs(5),
+            permanent_error_requeue_interval: Duration::from_secs(900),
+            secret_cache_ttl: Duration::from_secs(10),
+            full_resync_interval: None,
+            debounce: Duration::from_secs(2),
+            dry_run: false,
+            paused: false,
+            pause_file: None,
+            field_manager: String::from("annotations-from-secret"),
+            patch_strategy: PatchStrategy::Merge,
+            case_insensitive_keys: false,
+            substitution_passes: 1,
+            max_value_length: None,
+            requeue_jitter_percent: 10,
+            skip_empty: false,
+            trim_values: false,
+            compress_state: false,
+            secret_key_precedence: SecretKeyPrecedence::StringDataFirst,
+            annotation_templates: false,
+        }
+    }
+
+    pub fn is_namespace_watched(&self, namespace: &str) -> bool {
+        match &self.watch_namespaces {
+            Some(namespaces) => namespaces.iter().any(|watched| watched == namespace),
+            None => true,
+        }
+    }
+
+    // Cross-namespace Secret references are refused unless the source namespace is explicitly allowed
+    pub fn is_source_namespace_allowed(&self, resource_namespace: &str, secret_namespace: &str) -> bool {
+        resource_namespace == secret_namespace || self.allowed_source_namespaces.iter().any(|allowed| allowed == secret_namespace)
+    }
+
+    pub fn is_selected(&self, labels: &BTreeMap<String, String>) -> bool {
+        match &self.label_selector {
+            Some(label_selector) => label_selector.matches(labels),
+            None => true,
+        }
+    }
+}
+
+fn flag(name: &str) -> bool {
+    env::var(name).is_ok_and(|value| value == "true")
+}
+
+fn seconds(name: &str, default: u64) -> Duration {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(seconds) => Duration::from_secs(seconds),
+            Err(_) => {
+                warn!("Invalid {} {:?}, using the default of {} seconds", name, value, default);
+                Duration::from_secs(default)
+            }
+        },
+        Err(_) => Duration::from_secs(default),
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// The namespace this operator runs in, as exposed via the downward API or the mounted ServiceAccount
+pub fn operator_namespace() -> String {
+    if let Ok(namespace) = env::var("POD_NAMESPACE") {
+        return namespace;
+    }
+
+    match fs::read_to_string("/var/run/secrets/kubernetes.io/serviceaccount/namespace") {
+        Ok(namespace) => String::from(namespace.trim()),
+        Err(_) => String::from("default"),
+    }
+}
@@ -0,0 +1,37 @@
+use crate::index::ObjectKey;
+use k8s_openapi::api::core::v1::Secret;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+// Correlation IDs handed from a Secret event to the reconciles it triggers, so the whole fan-out of one Secret update
+// can be followed in the logs
+#[derive(Clone, Default)]
+pub struct Correlations {
+    pending: Arc<Mutex<HashMap<ObjectKey, String>>>,
+}
+
+impl Correlations {
+    pub fn propagate(&self, object: ObjectKey, id: String) {
+        self.pending.lock().unwrap().insert(object, id);
+    }
+
+    // The propagated ID when the reconcile was triggered by a Secret, a fresh one otherwise
+    pub fn take(&self, object: &ObjectKey) -> String {
+        self.pending.lock().unwrap().remove(object).unwrap_or_else(new_id)
+    }
+}
+
+// Every RandomState is seeded differently, which is all the randomness an ID needs
+fn new_id() -> String {
+    format!("{:016x}", RandomState::new().build_hasher().finish())
+}
+
+// Derived from the Secret's revision, so the controllers for each kind pick the same ID for the same update
+pub fn for_secret(secret: &Secret) -> String {
+    let mut hasher = DefaultHasher::new();
+    secret.metadata.uid.hash(&mut hasher);
+    secret.metadata.resource_version.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
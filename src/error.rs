@@ -0,0 +1,85 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum OperatorError {
+    #[error("Conflict while patching: {0}")]
+    PatchConflict(#[source] kube::Error),
+
+    #[error("Missing RBAC permission, the operator's ServiceAccount needs `{verb}` on `{resource}`")]
+    Forbidden { verb: String, resource: String, #[source] source: kube::Error },
+
+    #[error("Kubernetes API error: {0}")]
+    KubeApi(#[source] kube::Error),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Finalizer error: {0}")]
+    Finalizer(String),
+
+    // From a reconcile patching several objects, which carries on past failures and reports the first one
+    #[error("Failed to patch {failed} of {total} objects, first error: {source}")]
+    Partial { failed: usize, total: usize, #[source] source: Box<OperatorError> },
+}
+
+impl From<kube::runtime::finalizer::Error<OperatorError>> for OperatorError {
+    // Errors of the reconcile itself come through unchanged, so error_policy treats them as it always does
+    fn from(error: kube::runtime::finalizer::Error<OperatorError>) -> OperatorError {
+        use kube::runtime::finalizer::Error;
+        match error {
+            Error::ApplyFailed(error) | Error::CleanupFailed(error) => error,
+            Error::AddFinalizer(error) | Error::RemoveFinalizer(error) => error.into(),
+            error => OperatorError::Finalizer(error.to_string()),
+        }
+    }
+}
+
+// How likely a retry is to succeed, which decides how soon it happens
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCategory {
+    // Connection resets, timeouts and the like, usually gone within seconds
+    Transient,
+    // Caused by the resource or the operator's setup, e.g. an invalid patch, only a fix on the user's side helps
+    Permanent,
+    // Everything else, such as API server errors, retried with the regular backoff
+    Other,
+}
+
+impl OperatorError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            OperatorError::PatchConflict(_) => ErrorCategory::Transient,
+            OperatorError::Forbidden { .. } | OperatorError::Serialization(_) | OperatorError::Finalizer(_) => ErrorCategory::Permanent,
+            OperatorError::KubeApi(kube::Error::Api(response)) => match response.code {
+                400 | 403 | 422 => ErrorCategory::Permanent,
+                _ => ErrorCategory::Other,
+            },
+            OperatorError::KubeApi(kube::Error::SerdeError(_)) => ErrorCategory::Permanent,
+            OperatorError::KubeApi(_) => ErrorCategory::Transient,
+            OperatorError::Partial { source, .. } => source.category(),
+        }
+    }
+}
+
+impl From<kube::Error> for OperatorError {
+    // A 403 names the missing verb and resource in its message, which is far more actionable than the raw error
+    fn from(error: kube::Error) -> OperatorError {
+        if let kube::Error::Api(response) = &error {
+            if response.code == 403 {
+                if let Some((verb, resource)) = forbidden_permission(&response.message) {
+                    return OperatorError::Forbidden { verb, resource, source: error };
+                }
+            }
+        }
+        OperatorError::KubeApi(error)
+    }
+}
+
+// Parses `... cannot get resource "secrets" in API group "" ...` into the verb and resource
+fn forbidden_permission(message: &str) -> Option<(String, String)> {
+    let (_, rest) = message.split_once("cannot ")?;
+    let (verb, rest) = rest.split_once(' ')?;
+    let (_, rest) = rest.split_once("resource \"")?;
+    let (resource, _) = rest.split_once('"')?;
+    Some((String::from(verb), String::from(resource)))
+}
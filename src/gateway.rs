@@ -0,0 +1,25 @@
+use kube::core::GroupVersionKind;
+use kube::{discovery, Client, CustomResource, Resource};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::info;
+
+// Only the metadata is ever patched, so the spec is carried along without modelling the Gateway API types
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug)]
+#[kube(group = "gateway.networking.k8s.io", version = "v1", kind = "HTTPRoute", root = "HttpRoute", namespaced, schema = "disabled")]
+pub struct HttpRouteSpec {
+    #[serde(flatten)]
+    pub fields: BTreeMap<String, serde_json::Value>,
+}
+
+// The Gateway API CRDs are optional, so their absence only disables the HTTPRoute controller
+pub async fn is_installed(client: &Client) -> bool {
+    let gvk = GroupVersionKind::gvk(&HttpRoute::group(&()), &HttpRoute::version(&()), &HttpRoute::kind(&()));
+    match discovery::pinned_kind(client, &gvk).await {
+        Ok(_) => true,
+        Err(e) => {
+            info!("HTTPRoute CRD not found, skipping the HTTPRoute controller: {}", e);
+            false
+        }
+    }
+}
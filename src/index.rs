@@ -0,0 +1,93 @@
+use prometheus::IntGauge;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+// (namespace, name) of a referenced Secret
+pub type SecretKey = (String, String);
+
+// (kind, namespace, name) of a templated resource
+pub type ObjectKey = (String, String, String);
+
+// Maps every Secret to the resources referencing it, so Secret events don't need to scan all cached resources
+#[derive(Clone, Default)]
+pub struct SecretIndex {
+    inner: Arc<RwLock<Inner>>,
+    tracked_ingresses: Option<IntGauge>,
+}
+
+#[derive(Default)]
+struct Inner {
+    objects_by_secret: HashMap<SecretKey, HashSet<ObjectKey>>,
+    secrets_by_object: HashMap<ObjectKey, Vec<SecretKey>>,
+    // Ingresses in `secrets_by_object`, counted as they come and go so the gauge never needs a scan
+    ingresses: i64,
+}
+
+impl SecretIndex {
+    // Keeps `tracked_ingresses` at the number of Ingresses currently referencing a Secret
+    pub fn with_gauge(tracked_ingresses: IntGauge) -> SecretIndex {
+        SecretIndex { inner: Default::default(), tracked_ingresses: Some(tracked_ingresses) }
+    }
+
+    // Replaces whatever the object referenced before, keeping the index consistent when the annotation changes
+    pub fn update(&self, object: ObjectKey, secrets: Vec<SecretKey>) {
+        let mut inner = self.inner.write().unwrap();
+        inner.remove(&object);
+
+        if !secrets.is_empty() {
+            for secret in &secrets {
+                inner.objects_by_secret.entry(secret.clone()).or_default().insert(object.clone());
+            }
+            if object.0 == "Ingress" {
+                inner.ingresses += 1;
+            }
+            inner.secrets_by_object.insert(object, secrets);
+        }
+        self.record(&inner);
+    }
+
+    pub fn remove(&self, object: &ObjectKey) {
+        let mut inner = self.inner.write().unwrap();
+        inner.remove(object);
+        self.record(&inner);
+    }
+
+    fn record(&self, inner: &Inner) {
+        if let Some(tracked_ingresses) = &self.tracked_ingresses {
+            tracked_ingresses.set(inner.ingresses);
+        }
+    }
+
+    // Namespaces and names of all resources of `kind` referencing the Secret
+    pub fn lookup(&self, kind: &str, secret: &SecretKey) -> Vec<(String, String)> {
+        let inner = self.inner.read().unwrap();
+        match inner.objects_by_secret.get(secret) {
+            Some(objects) => objects.iter()
+                .filter(|(object_kind, _, _)| object_kind == kind)
+                .map(|(_, namespace, name)| (namespace.clone(), name.clone()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, object: &ObjectKey) {
+        let secrets = match self.secrets_by_object.remove(object) {
+            Some(secrets) => secrets,
+            None => return,
+        };
+        if object.0 == "Ingress" {
+            self.ingresses -= 1;
+        }
+
+        for secret in secrets {
+            if let Some(objects) = self.objects_by_secret.get_mut(&secret) {
+                objects.remove(object);
+                if objects.is_empty() {
+                    self.objects_by_secret.remove(&secret);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono::{self, Utc};
+use kube::api::PostParams;
+use kube::{Api, Client, Error};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+const LEASE_DURATION_SECONDS: i32 = 15;
+const RENEW_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct LeaderElection {
+    api: Api<Lease>,
+    name: String,
+    identity: String,
+}
+
+impl LeaderElection {
+    pub fn new(client: Client, namespace: &str, name: &str, identity: &str) -> LeaderElection {
+        LeaderElection {
+            api: Api::namespaced(client, namespace),
+            name: String::from(name),
+            identity: String::from(identity),
+        }
+    }
+
+    // Keeps competing for the lease in the background, publishing whether this replica holds it
+    pub fn start(self) -> watch::Receiver<bool> {
+        let (sender, receiver) = watch::channel(false);
+        tokio::spawn(self.run(sender));
+        receiver
+    }
+
+    async fn run(self, sender: watch::Sender<bool>) {
+        loop {
+            let is_leader = match self.try_acquire_or_renew().await {
+                Ok(is_leader) => is_leader,
+                Err(e) => {
+                    warn!("Failed to acquire or renew Lease {}: {:?}", self.name, e);
+                    false
+                }
+            };
+
+            if publish(&sender, is_leader) {
+                if is_leader {
+                    info!("Acquired Lease {} as {}, starting reconciliation", self.name, self.identity);
+                } else {
+                    info!("Lost Lease {}, pausing reconciliation", self.name);
+                }
+            }
+
+            tokio::time::sleep(RENEW_INTERVAL).await;
+        }
+    }
+
+    async fn try_acquire_or_renew(&self) -> Result<bool, Error> {
+        let now = Utc::now();
+
+        let mut lease = match self.api.get_opt(&self.name).await? {
+            Some(lease) => lease,
+            None => {
+                let lease = Lease {
+                    metadata: ObjectMeta {
+                        name: Some(self.name.clone()),
+                        ..ObjectMeta::default()
+                    },
+                    spec: Some(LeaseSpec {
+                        holder_identity: Some(self.identity.clone()),
+                        lease_duration_seconds: Some(LEASE_DURATION_SECONDS),
+                        acquire_time: Some(MicroTime(now)),
+                        renew_time: Some(MicroTime(now)),
+                        lease_transitions: Some(0),
+                    }),
+                };
+                return match self.api.create(&PostParams::default(), &lease).await {
+                    Ok(_) => Ok(true),
+                    Err(Error::Api(response)) if response.code == 409 => Ok(false),
+                    Err(e) => Err(e),
+                };
+            }
+        };
+
+        let mut spec = lease.spec.clone().unwrap_or_default();
+        let held_by_us = spec.holder_identity.as_deref() == Some(self.identity.as_str());
+        let expired = match &spec.renew_time {
+            Some(MicroTime(renew_time)) => {
+                let duration = spec.lease_duration_seconds.unwrap_or(LEASE_DURATION_SECONDS);
+                *renew_time + chrono::Duration::seconds(duration.into()) < now
+            }
+            None => true,
+        };
+
+        if !held_by_us && !expired {
+            return Ok(false);
+        }
+
+        if !held_by_us {
+            spec.holder_identity = Some(self.identity.clone());
+            spec.acquire_time = Some(MicroTime(now));
+            spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+        }
+        spec.lease_duration_seconds = Some(LEASE_DURATION_SECONDS);
+        spec.renew_time = Some(MicroTime(now));
+        lease.spec = Some(spec);
+
+        // The resourceVersion carried over from the read makes this fail with a conflict if someone else won
+        match self.api.replace(&self.name, &PostParams::default(), &lease).await {
+            Ok(_) => Ok(true),
+            Err(Error::Api(response)) if response.code == 409 => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Only a transition wakes the receivers, each of them reconciles everything on becoming leader, so a renew must not
+fn publish(sender: &watch::Sender<bool>, is_leader: bool) -> bool {
+    sender.send_if_modified(|current| std::mem::replace(current, is_leader) != is_leader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renew_without_a_transition_does_not_notify() {
+        let (sender, mut receiver) = watch::channel(false);
+
+        assert!(publish(&sender, true));
+        assert!(receiver.has_changed().unwrap());
+        receiver.borrow_and_update();
+
+        assert!(!publish(&sender, true));
+        assert!(!receiver.has_changed().unwrap());
+        assert!(*receiver.borrow());
+    }
+}
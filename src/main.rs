@@ -1,173 +1,1389 @@
-use kube::{Client, Api, runtime::controller::{Controller}, api::{Patch, PatchParams}, Error};
+mod access;
+mod backoff;
+mod cache;
+mod config;
+mod correlation;
+mod error;
+mod gateway;
+mod index;
+mod leader;
+mod metrics;
+mod pause;
+mod secret_watch;
+mod selector;
+mod server;
+mod substitution;
+mod telemetry;
+mod template;
+mod transforms;
+mod webhook;
+
+use kube::{Client, Api, runtime::controller::{self, Controller}, api::{Patch, PatchParams}, Error};
 use k8s_openapi::api::networking::v1::Ingress;
-use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::api::core::v1::{ConfigMap, Secret, Service};
+use k8s_openapi::NamespaceResourceScope;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::json;
-use futures::{StreamExt};
-use tracing::{info, error};
-use std::collections::BTreeMap;
+use futures::{FutureExt, StreamExt};
+use futures::future::BoxFuture;
+use futures::{TryFuture, TryFutureExt};
+use tracing::{debug, info, info_span, warn, error, instrument, Instrument, Span};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::ops::ControlFlow;
+use std::pin::pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
+use tokio::sync::{watch, Semaphore};
 use anyhow::Result;
-use k8s_openapi::Metadata;
+use kube::config::KubeConfigOptions;
 use kube::runtime::watcher;
 use kube::runtime::controller::Action;
-use kube::runtime::reflector::Lookup;
+use kube::runtime::finalizer::{finalizer, Event as FinalizerEvent};
+use kube::runtime::reflector::{ObjectRef, Store};
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use kube::{CustomResourceExt, Resource, ResourceExt};
+use crate::backoff::FailureBackoff;
+use crate::cache::SecretCache;
+use crate::correlation::Correlations;
+use crate::config::{Config, Mode, PatchStrategy, SecretKeyPrecedence};
+use crate::error::{ErrorCategory, OperatorError};
+use crate::index::{ObjectKey, SecretIndex, SecretKey};
+use crate::leader::LeaderElection;
+use crate::metrics::Metrics;
+use crate::secret_watch::SecretWatch;
 
 #[derive(Clone)]
 struct OperatorContext {
     client: Client,
+    config: Config,
+    reporter: Reporter,
+    metrics: Metrics,
+    leader: watch::Receiver<bool>,
+    paused: watch::Receiver<bool>,
+    index: SecretIndex,
+    secret_cache: SecretCache,
+    correlations: Correlations,
+    backoff: FailureBackoff,
+    // Shared by the controllers of every kind and namespace, None without a limit
+    reconcile_slots: Option<Arc<Semaphore>>,
+}
+
+impl OperatorContext {
+    fn recorder<K: Templated>(&self, resource: &K) -> Recorder {
+        Recorder::new(self.client.clone(), self.reporter.clone(), resource.object_ref(&()))
+    }
+}
+
+// Namespaced resources whose annotations can be templated, e.g. Ingress, Service and HTTPRoute
+trait Templated: Resource<DynamicType = (), Scope = NamespaceResourceScope> + Clone + DeserializeOwned + Serialize + Debug + Send + Sync + 'static {
+    // Whether this operator manages the resource at all, for checks a label selector can't express
+    fn is_managed(&self, _config: &Config) -> bool {
+        true
+    }
+
+    // JSON pointers within the spec to Secret names that may be templated in place
+    fn secret_name_fields(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+impl Templated for Ingress {
+    // With two ingress controllers in the cluster, only the Ingresses of the configured class are touched
+    fn is_managed(&self, config: &Config) -> bool {
+        let ingress_class = match &config.ingress_class {
+            Some(ingress_class) => ingress_class,
+            None => return true,
+        };
+
+        let class = self.spec.as_ref()
+            .and_then(|spec| spec.ingress_class_name.as_ref())
+            .or_else(|| self.annotations().get("kubernetes.io/ingress.class"));
+        class == Some(ingress_class)
+    }
+
+    // TLS Secrets are often named after something only the source Secret knows, e.g. a generated certificate
+    fn secret_name_fields(&self) -> Vec<String> {
+        let tls = self.spec.as_ref().and_then(|spec| spec.tls.as_ref());
+        (0..tls.map_or(0, Vec::len)).map(|index| format!("/tls/{}/secretName", index)).collect()
+    }
 }
 
-const SECRET_ANNOTATION: &str = "kirillorlov.pro/annotationsFromSecretName";
-const SECRET_ANNOTATION_STATE: &str = "kirillorlov.pro/annotationsFromSecretState";
+impl Templated for Service {}
+
+impl Templated for gateway::HttpRoute {}
+
+impl Templated for template::AnnotationTemplate {}
+
+const MAX_PATCH_ATTEMPTS: usize = 3;
+
+// A year, requeue intervals are clamped to it
+const MAX_REQUEUE_INTERVAL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+// Total size of all annotation keys and values the API server accepts
+const MAX_ANNOTATIONS_SIZE: usize = 256 * 1024;
+
+// Ingresses with a source annotation carry a finalizer, so their deletion is seen by the operator and cleaned up
+// after instead of the object simply vanishing from the cache. Cleanup only forgets what this replica tracks, so it
+// runs on every replica and while paused too, a deleted Ingress only waits for the operator to be running at all
+async fn reconcile_ingress(ingress: Arc<Ingress>, ctx: Arc<OperatorContext>) -> Result<Action, OperatorError> {
+    let finalized = ingress.finalizers().contains(&ctx.config.finalizer);
+    let deleting = ingress.meta().deletion_timestamp.is_some();
+
+    if (!*ctx.leader.borrow() || *ctx.paused.borrow()) && !(deleting && finalized) {
+        return reconcile(ingress, ctx).await;
+    }
+
+    // A dry run adds no finalizers, but never holds up the deletion of an Ingress that already has one
+    let sourced = has_source(ingress.as_ref(), &ctx.config)
+        && ctx.config.is_selected(ingress.labels())
+        && ingress.is_managed(&ctx.config)
+        && !ctx.config.dry_run;
+
+    // Partial objects, e.g. from cache replays, can't be patched, and reconcile already skips them with a warning
+    let (namespace, name) = match (ingress.namespace(), ingress.meta().name.clone()) {
+        (Some(namespace), Some(name)) => (namespace, name),
+        _ => return reconcile(ingress, ctx).await,
+    };
+    let api = Api::<Ingress>::namespaced(ctx.client.clone(), &namespace);
+
+    if sourced || (deleting && finalized) {
+        let finalizer_ctx = ctx.clone();
+        return finalizer(&api, &ctx.config.finalizer, ingress, |event| async move {
+            match event {
+                FinalizerEvent::Apply(ingress) => reconcile(ingress, finalizer_ctx).await,
+                FinalizerEvent::Cleanup(ingress) => cleanup(ingress.as_ref(), &finalizer_ctx).await,
+            }
+        }).await.map_err(OperatorError::from);
+    }
+
+    // The source annotation is gone, so once the templates are restored there's nothing left to clean up. The revert
+    // has just bumped the resourceVersion, so the finalizers themselves are what the patch checks for changes
+    let action = reconcile(ingress.clone(), ctx.clone()).await?;
+    if finalized && !deleting && !ctx.config.dry_run {
+        let finalizers = ingress.finalizers().iter().filter(|finalizer| **finalizer != ctx.config.finalizer).collect::<Vec<_>>();
+        let patch = serde_json::from_value::<json_patch::Patch>(json!([
+            { "op": "test", "path": "/metadata/finalizers", "value": ingress.finalizers() },
+            { "op": "replace", "path": "/metadata/finalizers", "value": finalizers },
+        ]))?;
+        api.patch(&name, &PatchParams::apply(&ctx.config.field_manager), &Patch::<()>::Json(patch)).await?;
+    }
+    Ok(action)
+}
+
+// Forgets everything kept about a deleted object before its finalizer is removed
+async fn cleanup<K: Templated>(resource: &K, ctx: &OperatorContext) -> Result<Action, OperatorError> {
+    if let Some(object) = object_key(resource) {
+        ctx.index.remove(&object);
+        ctx.backoff.reset(&object);
+    }
+
+    info!("Cleaned up deleted resource");
+    publish_event(&ctx.recorder(resource), EventType::Normal, "CleanedUp", String::from("Stopped managing annotations of the deleted resource")).await;
+    Ok(Action::await_change())
+}
+
+#[instrument(skip_all, fields(
+    resource.kind = K::kind(&()).as_ref(),
+    resource.name = resource.meta().name.as_deref(),
+    resource.namespace = resource.meta().namespace.as_deref(),
+    secret.name = tracing::field::Empty,
+    correlation_id = tracing::field::Empty,
+))]
+async fn reconcile<K: Templated>(resource: Arc<K>, ctx: Arc<OperatorContext>) -> Result<Action, OperatorError> {
+    if let Some(object) = object_key(resource.as_ref()) {
+        Span::current().record("correlation_id", ctx.correlations.take(&object).as_str());
+    }
+
+    // The watch is already filtered server-side, but a Secret mapping may still point at an object that stopped matching
+    if !ctx.config.is_selected(resource.labels()) || !resource.is_managed(&ctx.config) {
+        debug!("Skipping resource not matching the label selector or IngressClass");
+        if let Some(object) = object_key(resource.as_ref()) {
+            ctx.index.remove(&object);
+        }
+        return Ok(Action::await_change());
+    }
+
+    // Every watch event ends up here, so this keeps the index in step with the resource's current references.
+    // It happens before the Secret is fetched, so creating a Secret that was still missing reconciles its dependents right away
+    if let Some(object) = object_key(resource.as_ref()) {
+        ctx.index.update(object, referenced_secrets(resource.as_ref(), &ctx.config));
+    }
 
-async fn reconcile(ingress: Arc<Ingress>, ctx: Arc<OperatorContext>) -> Result<Action, Error> {
-    // collect all ingresses and save track to them: namespace + name + reference to secret(name)
-    // for each ingress -> save namespace and ensure we watch it for secrets (ref counting here?)
-    // for each reconcile of ingress -> trigger sync of that ingress
-    // for each change on ingress -> trigger sync of that ingress
-    // for each change on watched secret -> discover related ingress and reconcile them
-    //
+    // Everything gets reconciled again once this replica acquires the Lease or reconciliation is resumed
+    if !*ctx.leader.borrow() || *ctx.paused.borrow() {
+        return Ok(Action::await_change());
+    }
 
-    let annotations = ingress.metadata().annotations.as_ref();
-    if !annotations.is_some() {
+    // Leaves the resource exactly as it is, neither applying nor reverting, until the annotation goes away again
+    if resource.annotations().get(&ctx.config.annotations.disabled).is_some_and(|disabled| disabled == "true") {
+        debug!("Skipping disabled resource");
         return Ok(Action::await_change());
     }
 
-    let annotations = annotations.unwrap();
-    if !annotations.contains_key(SECRET_ANNOTATION) {
+    ctx.metrics.reconciles.inc();
+    let _timer = ctx.metrics.reconcile_duration.start_timer();
+
+    let annotations = resource.annotations();
+    let secret_name = secret_name(resource.as_ref(), &ctx.config);
+    let config_map_name = annotations.get(&ctx.config.annotations.config_map_name);
+    let labels_secret_name = annotations.get(&ctx.config.annotations.labels_secret_name);
+    let annotation_source_removed = secret_name.is_none() && config_map_name.is_none() && annotations.contains_key(&ctx.config.annotations.state);
+    if secret_name.is_none() && config_map_name.is_none() && labels_secret_name.is_none() && !annotation_source_removed {
         return Ok(Action::await_change());
     }
 
-    let secret_name = annotations.get(SECRET_ANNOTATION).unwrap();
+    let current_namespace = match resource.meta().namespace.as_ref() {
+        Some(namespace) => namespace,
+        None => {
+            warn!("Skipping resource without a namespace");
+            return Ok(Action::await_change());
+        }
+    };
 
-    let current_namespace = ingress.metadata().namespace.as_ref().unwrap();
+    if !ctx.config.is_namespace_watched(current_namespace) {
+        warn!("Skipping resource outside of the watched namespaces");
+        return Ok(Action::await_change());
+    }
 
-    let secret_api = Api::<Secret>::namespaced(ctx.client.clone(), current_namespace);
+    // The source annotation is gone, the patch triggers another reconcile which then picks up a remaining
+    // labelsFromSecretName
+    if annotation_source_removed {
+        let api = Api::<K>::namespaced(ctx.client.clone(), current_namespace);
+        revert(&api, resource.as_ref(), &ctx, &ctx.recorder(resource.as_ref())).await?;
+        return Ok(Action::await_change());
+    }
 
-    let secret = match secret_api.get(secret_name).await {
-        Ok(secret) => secret,
-        Err(e) => {
-            error!("Failed to get Secret: {:?}", e);
-            return Err(e.into());
+    let mut fetched = FetchedSecrets::new();
+    let annotation_replacements = if secret_name.is_some() || config_map_name.is_some() {
+        // ConfigMap values go in first so that Secret values win on key collision
+        let mut replacements = BTreeMap::new();
+
+        if let Some(config_map_name) = config_map_name {
+            let config_map_api = Api::<ConfigMap>::namespaced(ctx.client.clone(), current_namespace);
+
+            let config_map = match config_map_api.get(config_map_name).await {
+                Ok(config_map) => config_map,
+                Err(e) => {
+                    error!("Failed to get ConfigMap: {:?}", e);
+                    return Err(e.into());
+                }
+            };
+
+            replacements.extend(config_map_replacements(&config_map));
         }
+
+        if let Some(secret_references) = secret_name {
+            Span::current().record("secret.name", secret_references.as_str());
+
+            match fetch_secrets(secret_references, current_namespace, resource.as_ref(), &ctx, &mut fetched).await? {
+                ControlFlow::Continue(secret_values) => replacements.extend(select_secret_keys(secret_values, annotations, &ctx.config)),
+                // The substituted values went stale with the Secret, the templates are applied again once it is back
+                ControlFlow::Break(SecretsUnavailable::Missing) if annotations.contains_key(&ctx.config.annotations.state) => {
+                    let api = Api::<K>::namespaced(ctx.client.clone(), current_namespace);
+                    revert(&api, resource.as_ref(), &ctx, &ctx.recorder(resource.as_ref())).await?;
+                    return Ok(SecretsUnavailable::Missing.action(current_namespace, &ctx));
+                }
+                ControlFlow::Break(unavailable) => return Ok(unavailable.action(current_namespace, &ctx)),
+            }
+        }
+
+        // Each named Secret is fetched once however many placeholders use it, a missing one only leaves those
+        // placeholders unresolved
+        for named_secret in substitution::named_secrets(annotations, &ctx.config) {
+            match fetch_secrets(&named_secret, current_namespace, resource.as_ref(), &ctx, &mut fetched).await? {
+                ControlFlow::Continue(secret_values) => {
+                    replacements.extend(secret_values.into_iter().map(|(key, value)| (format!("{}/{}", named_secret, key), value)));
+                }
+                ControlFlow::Break(_) => warn!("Placeholders referencing Secret {} are left unresolved", named_secret),
+            }
+        }
+
+        Some(replacements)
+    } else {
+        None
     };
 
-    let api = Api::<Ingress>::namespaced(ctx.client.clone(), &current_namespace);
+    let label_replacements = match labels_secret_name {
+        Some(secret_references) => match fetch_secrets(secret_references, current_namespace, resource.as_ref(), &ctx, &mut fetched).await? {
+            ControlFlow::Continue(secret_values) => Some(select_secret_keys(secret_values, annotations, &ctx.config)),
+            ControlFlow::Break(unavailable) => return Ok(unavailable.action(current_namespace, &ctx)),
+        },
+        None => None,
+    };
+
+    let api = Api::<K>::namespaced(ctx.client.clone(), current_namespace);
+
+    let recorder = ctx.recorder(resource.as_ref());
 
-    match apply(api, ingress, secret).await {
-        Ok(_) => Ok(Action::requeue(Duration::from_secs(300))), // Requeue after 5 minutes
+    match apply(api, resource.clone(), annotation_replacements, label_replacements, &ctx, &recorder).await {
+        Ok(patched) => {
+            ctx.metrics.record_reconcile(current_namespace, if patched > 0 { "patched" } else { "noop" });
+            Ok(Action::requeue(requeue_interval(resource.as_ref(), &ctx.config)))
+        }
         Err(e) => Err(e),
     }
 }
 
-async fn apply(api: Api<Ingress>, ingress: Arc<Ingress>, secret: Secret) -> Result<i32, Error> {
+// The requeueSeconds annotation overrides REQUEUE_SECONDS for a single resource, invalid values fall back to it
+fn requeue_interval<K: Templated>(resource: &K, config: &Config) -> Duration {
+    let interval = match resource.annotations().get(&config.annotations.requeue_seconds) {
+        Some(requeue_seconds) => match requeue_seconds.trim().parse::<u64>() {
+            Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+            _ => {
+                warn!("Ignoring invalid {} {:?}, using the default requeue interval", config.annotations.requeue_seconds, requeue_seconds);
+                config.requeue_interval
+            }
+        },
+        None => config.requeue_interval,
+    };
+    jitter(interval, resource, config.requeue_jitter_percent)
+}
+
+// Shifts the interval by up to `percent` either way, so resources created together don't requeue in lockstep. The
+// shift is derived from the object's key, which keeps each resource on a steady period
+fn jitter<K: Templated>(interval: Duration, resource: &K, percent: u32) -> Duration {
+    // Far beyond any sensible interval, but small enough that neither the math here nor the scheduler's timer overflows
+    let interval = interval.min(MAX_REQUEUE_INTERVAL);
+    let range = interval.as_millis() as u64 * u64::from(percent) / 100;
+    if range == 0 {
+        return interval;
+    }
+
+    let mut hasher = DefaultHasher::new();
+    object_key(resource).hash(&mut hasher);
+    let offset = hasher.finish() % (2 * range + 1);
+    (interval + Duration::from_millis(offset)).saturating_sub(Duration::from_millis(range))
+}
+
+// Why the referenced Secrets couldn't be read, the details are already logged at that point
+enum SecretsUnavailable {
+    // Invalid or disallowed references, which only a change to the resource can fix
+    Skip,
+    Missing,
+}
+
+impl SecretsUnavailable {
+    // Ends the reconcile, which is the only place a missing Secret counts as its result
+    fn action(&self, namespace: &str, ctx: &OperatorContext) -> Action {
+        match self {
+            SecretsUnavailable::Skip => Action::await_change(),
+            SecretsUnavailable::Missing => {
+                ctx.metrics.record_reconcile(namespace, "secret_missing");
+                Action::requeue(ctx.config.missing_secret_requeue_interval)
+            }
+        }
+    }
+}
+
+// Values of the Secrets already read during one reconcile, so each is fetched at most once
+type FetchedSecrets = HashMap<SecretKey, BTreeMap<String, String>>;
+
+// Merges the values of every referenced Secret, or breaks out when any of them can't be read
+async fn fetch_secrets<K: Templated>(
+    secret_references: &str,
+    current_namespace: &str,
+    resource: &K,
+    ctx: &OperatorContext,
+    fetched: &mut FetchedSecrets,
+) -> Result<ControlFlow<SecretsUnavailable, BTreeMap<String, String>>, OperatorError> {
+    let references = match parse_secret_references(secret_references, current_namespace) {
+        Some(references) => references,
+        None => {
+            error!("Invalid Secret reference {:?}, expected a comma-separated list of `name` or `namespace/name`", secret_references);
+            return Ok(ControlFlow::Break(SecretsUnavailable::Skip));
+        }
+    };
+
+    // Later Secrets override earlier ones on key conflicts
     let mut replacements = BTreeMap::new();
-    if secret.data.is_some() {
-        let data = secret.data.as_ref().unwrap();
+    for (secret_namespace, secret_name) in references {
+        if !ctx.config.is_namespace_watched(secret_namespace) {
+            warn!("Skipping Secret {}/{} outside of the watched namespaces", secret_namespace, secret_name);
+            return Ok(ControlFlow::Break(SecretsUnavailable::Skip));
+        }
 
-        for (k, v) in data {
-            let str = String::from_utf8(v.0.clone()).unwrap();
-            replacements.insert(k.clone(), str.clone());
+        if !ctx.config.is_source_namespace_allowed(current_namespace, secret_namespace) {
+            warn!("Refusing to read Secret {}/{} as its namespace is not in ALLOWED_SOURCE_NAMESPACES", secret_namespace, secret_name);
+            let note = format!("Secret {}/{} is in a namespace not allowed as a source", secret_namespace, secret_name);
+            publish_event(&ctx.recorder(resource), EventType::Warning, "SecretNamespaceNotAllowed", note).await;
+            return Ok(ControlFlow::Break(SecretsUnavailable::Skip));
         }
+
+        let secret_key = (String::from(secret_namespace), String::from(secret_name));
+        if let Some(secret_values) = fetched.get(&secret_key) {
+            replacements.extend(secret_values.clone());
+            continue;
+        }
+
+        let secret = match get_secret(&secret_key, ctx).await {
+            Ok(secret) => secret,
+            // Common while bootstrapping when the resource is applied before its Secret, so this is not a failure.
+            // The Secret watch picks the creation up, the requeue is only a safety net
+            Err(Error::Api(response)) if response.code == 404 => {
+                warn!("Secret {}/{} not found, retrying in {:?}", secret_namespace, secret_name, ctx.config.missing_secret_requeue_interval);
+                let note = format!("Secret {}/{} not found", secret_namespace, secret_name);
+                publish_event(&ctx.recorder(resource), EventType::Warning, "SecretNotFound", note).await;
+                return Ok(ControlFlow::Break(SecretsUnavailable::Missing));
+            }
+            Err(e) => {
+                error!("Failed to get Secret {}/{}: {:?}", secret_namespace, secret_name, e);
+                return Err(e.into());
+            }
+        };
+
+        let secret_values = secret_replacements(&secret, &ctx.config);
+        debug!("Secret {}/{} contributed keys {:?}", secret_namespace, secret_name, secret_values.keys().collect::<Vec<_>>());
+        replacements.extend(secret_values.clone());
+        fetched.insert(secret_key, secret_values);
     }
-    if secret.string_data.is_some() {
-        let string_data = secret.string_data.as_ref().unwrap();
-        for (k, v) in string_data {
-            replacements.insert(k.clone(), v.clone());
+
+    Ok(ControlFlow::Continue(replacements))
+}
+
+// With a secretKeys annotation only the listed keys of the source Secrets are used, the rest are dropped right away
+fn select_secret_keys(mut secret_values: BTreeMap<String, String>, annotations: &BTreeMap<String, String>, config: &Config) -> BTreeMap<String, String> {
+    if let Some(secret_keys) = annotations.get(&config.annotations.secret_keys) {
+        let secret_keys = secret_keys.split(',').map(str::trim).filter(|key| !key.is_empty()).collect::<Vec<_>>();
+        secret_values.retain(|key, _| secret_keys.contains(&key.as_str()));
+        debug!("Using Secret keys {:?}", secret_values.keys().collect::<Vec<_>>());
+    }
+    secret_values
+}
+
+// Reads a Secret through the shared cache, only misses hit the API server
+async fn get_secret(secret_key: &SecretKey, ctx: &OperatorContext) -> Result<Secret, Error> {
+    if let Some(secret) = ctx.secret_cache.get(secret_key) {
+        return Ok(secret);
+    }
+
+    let (secret_namespace, secret_name) = secret_key;
+    let secret_api = Api::<Secret>::namespaced(ctx.client.clone(), secret_namespace);
+
+    let fetch_span = info_span!("secret_fetch", secret.namespace = secret_namespace.as_str(), secret.name = secret_name.as_str());
+    let fetch_timer = ctx.metrics.secret_fetch_duration.with_label_values(&[secret_namespace]).start_timer();
+    let fetched = secret_api.get(secret_name).instrument(fetch_span).await;
+    fetch_timer.observe_duration();
+
+    let secret = fetched?;
+    ctx.secret_cache.insert(secret_key.clone(), secret.clone());
+    Ok(secret)
+}
+
+// Splits `namespace/name` into its parts, falling back to the resource namespace for a bare `name`
+fn parse_secret_reference<'a>(reference: &'a str, default_namespace: &'a str) -> Option<(&'a str, &'a str)> {
+    match reference.split_once('/') {
+        None => Some((default_namespace, reference)),
+        Some((namespace, name)) if !namespace.is_empty() && !name.is_empty() && !name.contains('/') => Some((namespace, name)),
+        Some(_) => None,
+    }
+}
+
+// Parses a comma-separated list of Secret references, rejecting the whole list if any entry is invalid
+fn parse_secret_references<'a>(references: &'a str, default_namespace: &'a str) -> Option<Vec<(&'a str, &'a str)>> {
+    references.split(',')
+        .map(str::trim)
+        .filter(|reference| !reference.is_empty())
+        .map(|reference| parse_secret_reference(reference, default_namespace))
+        .collect()
+}
+
+fn secret_replacements(secret: &Secret, config: &Config) -> BTreeMap<String, String> {
+    let mut data = BTreeMap::new();
+    if let Some(secret_data) = secret.data.as_ref() {
+        for (k, v) in secret_data {
+            match String::from_utf8(v.0.clone()) {
+                Ok(str) => {
+                    data.insert(k.clone(), str);
+                }
+                Err(_) => warn!("Skipping Secret key {} as its value is not valid UTF-8", k),
+            }
         }
     }
+    let string_data = secret.string_data.clone().unwrap_or_default();
 
-    let mut updated_annotations = BTreeMap::new();
-    let mut old_values = BTreeMap::new();
+    for key in string_data.keys().filter(|key| data.contains_key(*key)) {
+        debug!("Secret key {} is set in both data and stringData, using {:?}", key, config.secret_key_precedence);
+    }
 
-    let mut old_items : BTreeMap<String, String> = BTreeMap::new();
-    if ingress.metadata.annotations.as_ref().unwrap().contains_key(SECRET_ANNOTATION_STATE) {
-        let old_values_string = ingress.metadata.annotations.as_ref().unwrap().get(SECRET_ANNOTATION_STATE).unwrap();
-        let result = serde_json::from_str(old_values_string);
-        if result.is_ok() {
-            old_items = result.unwrap();
+    // Whatever goes in last wins
+    let (mut replacements, preferred) = match config.secret_key_precedence {
+        SecretKeyPrecedence::DataFirst => (string_data, data),
+        SecretKeyPrecedence::StringDataFirst => (data, string_data),
+    };
+    replacements.extend(preferred);
+
+    // Values created with `--from-file` usually end in a newline that has no place in an annotation
+    if config.trim_values {
+        for value in replacements.values_mut() {
+            *value = String::from(value.trim());
         }
     }
+    replacements
+}
 
-    for (key, value) in ingress.metadata().annotations.as_ref().unwrap() {
-        if key == "kubectl.kubernetes.io/last-applied-configuration" {
-            continue;
+fn config_map_replacements(config_map: &ConfigMap) -> BTreeMap<String, String> {
+    let mut replacements = BTreeMap::new();
+    if let Some(binary_data) = config_map.binary_data.as_ref() {
+        for (k, v) in binary_data {
+            match String::from_utf8(v.0.clone()) {
+                Ok(str) => {
+                    replacements.insert(k.clone(), str);
+                }
+                Err(_) => warn!("Skipping ConfigMap key {} as its value is not valid UTF-8", k),
+            }
         }
-        if key == SECRET_ANNOTATION_STATE {
-            continue;
+    }
+    if let Some(data) = config_map.data.as_ref() {
+        for (k, v) in data {
+            replacements.insert(k.clone(), v.clone());
         }
+    }
+    replacements
+}
 
-        for (replacement_key, replacement_value) in &replacements {
-            let x = String::from("$") + replacement_key.as_str() + "$";
+async fn apply<K: Templated>(
+    api: Api<K>,
+    resource: Arc<K>,
+    annotation_replacements: Option<BTreeMap<String, String>>,
+    label_replacements: Option<BTreeMap<String, String>>,
+    ctx: &OperatorContext,
+    recorder: &Recorder,
+) -> Result<i32, OperatorError> {
+    let name = match resource.meta().name.as_ref() {
+        Some(name) => name,
+        None => {
+            warn!("Skipping patch of resource without a name");
+            return Ok(0);
+        }
+    };
+
+    let mut resource = resource.clone();
+    let mut attempt = 1;
+    loop {
+        // Reconcile only gets here with a source, in an annotation or the SECRET_NAME_LABEL label, so a missing one
+        // means the object changed underneath, e.g. on the refetch after a conflict
+        if !has_source(resource.as_ref(), &ctx.config) {
+            debug!("Resource no longer references a source, skipping patch");
+            return Ok(0);
+        }
+        let annotations = resource.annotations();
+
+        // Unchanged Secret contents with every annotation already substituted means there is nothing to write,
+        // which keeps the resourceVersion stable for other controllers watching the resource
+        let mut removed_annotations = Vec::new();
+        let mut updated_annotations = match &annotation_replacements {
+            Some(replacements) => match substitution::substitute_annotations(annotations, replacements, &ctx.config)? {
+                Some(update) => {
+                    removed_annotations = update.removed;
+                    update.annotations
+                }
+                None => BTreeMap::new(),
+            },
+            None => BTreeMap::new(),
+        };
 
-            let mut original_value = value;
+        let mut updated_labels = BTreeMap::new();
+        if let Some(replacements) = &label_replacements {
+            if let Some(update) = substitution::substitute_labels(resource.labels(), annotations, replacements, &ctx.config)? {
+                updated_labels = update.labels;
+                updated_annotations.insert(ctx.config.annotations.label_state.clone(), update.state);
+            }
+        }
 
-            if old_items.contains_key(key) {
-                original_value = old_items.get(key).unwrap();
+        // Opt-in through specTemplates or placeholders in Secret name fields, rendered with the same values as the
+        // annotations
+        let mut spec_updates = BTreeMap::new();
+        let mut cleared_annotations = removed_annotations.iter().map(String::as_str).collect::<Vec<_>>();
+        if let Some(replacements) = &annotation_replacements {
+            if let Some(update) = substitution::substitute_spec(&resource_spec(resource.as_ref())?, &resource.secret_name_fields(), annotations, replacements, &ctx.config)? {
+                spec_updates = update.values;
+                match update.state {
+                    Some(state) => {
+                        updated_annotations.insert(ctx.config.annotations.spec_state.clone(), state);
+                    }
+                    None => cleared_annotations.push(ctx.config.annotations.spec_state.as_str()),
+                }
             }
+        }
+
+        // Recorded for every reconcile, so growing state shows up well before a patch gets rejected
+        let state = updated_annotations.get(&ctx.config.annotations.state).or_else(|| annotations.get(&ctx.config.annotations.state));
+        if let Some(state) = state {
+            let namespace = resource.namespace().unwrap_or_default();
+            ctx.metrics.state_annotation_size.with_label_values(&[&namespace]).observe(state.len() as f64);
+        }
 
-            if original_value.as_str().contains(x.as_str()) {
-                let replaced_value = original_value.replace(x.as_str(), replacement_value.as_str());
+        // A plan left behind by an earlier dry run no longer describes anything once changes are really applied
+        let clear_plan = !ctx.config.dry_run && annotations.contains_key(&ctx.config.annotations.dry_run_plan);
 
-                if replaced_value != value.as_str() {
-                    updated_annotations.insert(key.clone(), replaced_value);
-                    old_values.insert(key.clone(), original_value.clone());
+        if clear_plan {
+            cleared_annotations.push(ctx.config.annotations.dry_run_plan.as_str());
+        }
+
+        if updated_annotations.is_empty() && updated_labels.is_empty() && spec_updates.is_empty() && cleared_annotations.is_empty() {
+            debug!("Secret unchanged and annotations up to date, skipping patch");
+            return Ok(0);
+        }
+
+        // The API server rejects the patch with an opaque error past this, so it is reported here instead
+        let projected_size = projected_annotation_size(annotations, &updated_annotations, &cleared_annotations);
+        if projected_size > MAX_ANNOTATIONS_SIZE {
+            let offending_key = updated_annotations.iter()
+                .max_by_key(|(key, value)| key.len() + value.len())
+                .map(|(key, _)| key.as_str())
+                .unwrap_or_default();
+            let note = format!("Annotations would total {} bytes, over the {} byte limit, largest substituted annotation is {}", projected_size, MAX_ANNOTATIONS_SIZE, offending_key);
+            error!("{}, skipping patch", note);
+            publish_event(recorder, EventType::Warning, "AnnotationsTooLarge", note).await;
+            return Ok(0);
+        }
+
+        let affected_keys = updated_annotations.keys()
+            .filter(|key| **key != ctx.config.annotations.state && **key != ctx.config.annotations.checksum && **key != ctx.config.annotations.label_state)
+            .filter(|key| **key != ctx.config.annotations.spec_state)
+            .chain(updated_labels.keys())
+            .chain(&removed_annotations)
+            .cloned()
+            .chain(spec_updates.keys().map(|pointer| format!("spec{}", pointer)))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        // Update the resource with new annotations, the resourceVersion makes a stale read fail with a conflict
+        let patch = match ctx.config.patch_strategy {
+            PatchStrategy::Merge => {
+                // Null deletes a key in a merge patch
+                let mut patch_annotations = serde_json::to_value(&updated_annotations)?;
+                for key in &cleared_annotations {
+                    patch_annotations[*key] = serde_json::Value::Null;
+                }
+                let mut patch = json!({
+                    "metadata": {
+                        "resourceVersion": resource.meta().resource_version,
+                        "annotations": patch_annotations,
+                        "labels": updated_labels,
+                    }
+                });
+                // Arrays can't be patched element-wise by a merge patch, so the whole updated spec is sent, which the
+                // resourceVersion above keeps from overwriting a concurrent edit
+                if !spec_updates.is_empty() {
+                    patch["spec"] = spec_with(resource.as_ref(), &spec_updates)?;
                 }
+                Patch::Merge(patch)
             }
+            PatchStrategy::Json => Patch::Json(json_patch(resource.meta(), &updated_annotations, &cleared_annotations, &updated_labels, &spec_updates)?),
+        };
+
+        if ctx.config.dry_run {
+            info!(annotations = ?updated_annotations, labels = ?updated_labels, "Dry run, would patch annotations");
+            ctx.metrics.dry_run_patches.inc();
+            write_dry_run_plan(&api, name, resource.as_ref(), &updated_annotations, ctx).await?;
+            return Ok(0);
         }
-    }
 
-    if updated_annotations.len() == 0 {
-        return Ok(0);
+        match api.patch(name, &PatchParams::apply(&ctx.config.field_manager), &patch).instrument(info_span!("patch", attempt)).await {
+            Ok(_) => {
+                info!(keys = affected_keys.as_str(), "Patched resource with new annotations");
+                ctx.metrics.patches.inc();
+                let note = format!("Applied annotations: {}", affected_keys);
+                publish_event(recorder, EventType::Normal, "AnnotationsApplied", note).await;
+                return Ok(1);
+            }
+            Err(Error::Api(response)) if response.code == 409 && attempt < MAX_PATCH_ATTEMPTS => {
+                warn!("Conflict while patching, refetching and retrying (attempt {}/{})", attempt, MAX_PATCH_ATTEMPTS);
+                resource = Arc::new(api.get(name).await?);
+                attempt += 1;
+            }
+            // A JSON patch also fails when a path it relies on changed since the read, e.g. another controller
+            // dropping the annotations, which a single retry against a fresh copy tells apart from a broken patch
+            Err(Error::Api(response)) if response.code == 422 && ctx.config.patch_strategy == PatchStrategy::Json && attempt == 1 => {
+                warn!("Patch rejected, likely because of a stale read, refetching and retrying once: {}", response.message);
+                resource = Arc::new(api.get(name).await?);
+                attempt += 1;
+            }
+            Err(e) => {
+                error!("Failed to patch resource: {:?}", e);
+                return match e {
+                    Error::Api(response) if response.code == 409 => Err(OperatorError::PatchConflict(Error::Api(response))),
+                    e => Err(e.into()),
+                };
+            }
+        }
     }
+}
 
-    updated_annotations.insert(String::from(SECRET_ANNOTATION_STATE), serde_json::to_string(&old_values).unwrap());
+// Records what a dry run would have changed on the resource itself, so it shows up in `kubectl get -o yaml` and GitOps
+// diffs. Only this one annotation is written
+async fn write_dry_run_plan<K: Templated>(
+    api: &Api<K>,
+    name: &str,
+    resource: &K,
+    updated_annotations: &BTreeMap<String, String>,
+    ctx: &OperatorContext,
+) -> Result<(), OperatorError> {
+    let keys = &ctx.config.annotations;
+    let plan = updated_annotations.iter()
+        .filter(|(key, _)| **key != keys.state && **key != keys.checksum && **key != keys.label_state && **key != keys.spec_state)
+        .collect::<BTreeMap<_, _>>();
+    let plan = serde_json::to_string(&plan)?;
+
+    if resource.annotations().get(&keys.dry_run_plan) == Some(&plan) {
+        return Ok(());
+    }
 
-    // Update the Ingress with new annotations
     let patch = json!({
         "metadata": {
-            "annotations": updated_annotations,
+            "annotations": {
+                &keys.dry_run_plan: plan,
+            }
         }
     });
+    api.patch(name, &PatchParams::apply(&ctx.config.field_manager), &Patch::Merge(&patch)).await?;
+    Ok(())
+}
 
-    let ingress_name = String::from(ingress.name().clone().unwrap());
-    match api.patch(ingress_name.as_ref(), &PatchParams::apply("my-operator"), &Patch::Merge(&patch)).await {
-        Ok(_) => {
-            info!("Patched Ingress {} with new annotations", ingress_name);
-            Ok(1)
+// Restores every templated annotation to its template and drops the operator's bookkeeping annotations
+async fn revert<K: Templated>(api: &Api<K>, resource: &K, ctx: &OperatorContext, recorder: &Recorder) -> Result<(), OperatorError> {
+    let name = match resource.meta().name.as_ref() {
+        Some(name) => name,
+        None => {
+            warn!("Skipping revert of resource without a name");
+            return Ok(());
         }
-        Err(e) => {
-            error!("Failed to patch Ingress: {:?}", e);
-            Err(e.into())
+    };
+
+    let state = substitution::parse_state(resource.annotations().get(&ctx.config.annotations.state)).unwrap_or_default();
+
+    // Null deletes a key in a merge patch
+    let mut reverted_annotations = state.into_iter()
+        .map(|(key, entry)| (key, entry.into_original()))
+        .collect::<BTreeMap<_, _>>();
+    // Spec fields go back to what they held before they were first templated
+    let spec_state = substitution::parse_state(resource.annotations().get(&ctx.config.annotations.spec_state))
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(pointer, entry)| Some((pointer, entry.into_original()?)))
+        .collect::<BTreeMap<_, _>>();
+
+    let affected_keys = reverted_annotations.keys()
+        .cloned()
+        .chain(spec_state.keys().map(|pointer| format!("spec{}", pointer)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    reverted_annotations.insert(ctx.config.annotations.state.clone(), None);
+    reverted_annotations.insert(ctx.config.annotations.checksum.clone(), None);
+    if resource.annotations().contains_key(&ctx.config.annotations.spec_state) {
+        reverted_annotations.insert(ctx.config.annotations.spec_state.clone(), None);
+    }
+
+    if ctx.config.dry_run {
+        info!(annotations = ?reverted_annotations, spec = ?spec_state, "Dry run, would revert annotations");
+        ctx.metrics.dry_run_patches.inc();
+        return Ok(());
+    }
+
+    let mut patch = json!({
+        "metadata": {
+            "annotations": reverted_annotations,
         }
+    });
+    // The whole spec replaces arrays such as rules and tls wholesale, so a concurrent edit has to fail with a
+    // conflict rather than be overwritten, the retry reverts from a fresh read
+    if !spec_state.is_empty() {
+        patch["metadata"]["resourceVersion"] = json!(resource.meta().resource_version);
+        patch["spec"] = spec_with(resource, &spec_state)?;
+    }
+
+    if let Err(e) = api.patch(name, &PatchParams::apply(&ctx.config.field_manager), &Patch::Merge(&patch)).await {
+        error!("Failed to revert resource: {:?}", e);
+        return match e {
+            Error::Api(response) if response.code == 409 => Err(OperatorError::PatchConflict(Error::Api(response))),
+            e => Err(e.into()),
+        };
+    }
+
+    info!(keys = affected_keys.as_str(), "Reverted annotations to their templates");
+    ctx.metrics.patches.inc();
+    let note = format!("Reverted annotations: {}", affected_keys);
+    publish_event(recorder, EventType::Normal, "AnnotationsReverted", note).await;
+    Ok(())
+}
+
+// Adds or replaces exactly the changed keys, including the state annotation, in a single JSON patch. Setting the
+// resourceVersion still has the API server reject a stale read with a conflict
+fn json_patch(
+    meta: &ObjectMeta,
+    updated_annotations: &BTreeMap<String, String>,
+    cleared_annotations: &[&str],
+    updated_labels: &BTreeMap<String, String>,
+    spec_updates: &BTreeMap<String, String>,
+) -> Result<json_patch::Patch, serde_json::Error> {
+    let mut operations = Vec::new();
+
+    if let Some(resource_version) = &meta.resource_version {
+        operations.push(json!({ "op": "replace", "path": "/metadata/resourceVersion", "value": resource_version }));
+    }
+
+    // Only ever called with keys that exist, removing a missing one fails the whole patch
+    for key in cleared_annotations {
+        let path = format!("/metadata/annotations/{}", key.replace('~', "~0").replace('/', "~1"));
+        operations.push(json!({ "op": "remove", "path": path }));
+    }
+
+    for (field, existing, updated) in [("annotations", &meta.annotations, updated_annotations), ("labels", &meta.labels, updated_labels)] {
+        if updated.is_empty() {
+            continue;
+        }
+        // Adding to a missing map fails, and adding the map itself would replace an existing one
+        if existing.is_none() {
+            operations.push(json!({ "op": "add", "path": format!("/metadata/{}", field), "value": {} }));
+        }
+        for (key, value) in updated {
+            let path = format!("/metadata/{}/{}", field, key.replace('~', "~0").replace('/', "~1"));
+            operations.push(json!({ "op": "add", "path": path, "value": value }));
+        }
+    }
+
+    // substitute_spec only returns pointers to existing string fields, so replace always applies
+    for (pointer, value) in spec_updates {
+        operations.push(json!({ "op": "replace", "path": format!("/spec{}", pointer), "value": value }));
+    }
+
+    serde_json::from_value(serde_json::Value::Array(operations))
+}
+
+fn resource_spec<K: Templated>(resource: &K) -> Result<serde_json::Value, serde_json::Error> {
+    let mut resource = serde_json::to_value(resource)?;
+    Ok(resource.get_mut("spec").map(serde_json::Value::take).unwrap_or_default())
+}
+
+// The resource's spec with the string fields at the given JSON pointers replaced
+fn spec_with<K: Templated>(resource: &K, updates: &BTreeMap<String, String>) -> Result<serde_json::Value, serde_json::Error> {
+    let mut spec = resource_spec(resource)?;
+    for (pointer, value) in updates {
+        if let Some(field) = spec.pointer_mut(pointer) {
+            *field = serde_json::Value::String(value.clone());
+        }
+    }
+    Ok(spec)
+}
+
+// Annotation keys and values as they'd be after the patch, counted the way the API server does
+fn projected_annotation_size(annotations: &BTreeMap<String, String>, updated_annotations: &BTreeMap<String, String>, cleared_annotations: &[&str]) -> usize {
+    let mut projected = annotations.clone();
+    projected.extend(updated_annotations.clone());
+    projected.retain(|key, _| !cleared_annotations.contains(&key.as_str()));
+    projected.iter().map(|(key, value)| key.len() + value.len()).sum()
+}
+
+async fn publish_event(recorder: &Recorder, type_: EventType, reason: &str, note: String) {
+    let event = Event {
+        type_,
+        reason: String::from(reason),
+        note: Some(note),
+        action: String::from("Reconcile"),
+        secondary: None,
+    };
+
+    if let Err(e) = recorder.publish(event).await {
+        warn!("Failed to publish {} event: {:?}", reason, e);
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let client = Client::try_default().await?;
+    if std::env::args().any(|arg| arg == "--version" || arg == "-V") {
+        println!("{} {} ({})", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), env!("GIT_SHA"));
+        return Ok(());
+    }
+
+    // `kubectl apply -f` accepts JSON, so the CRD can be installed straight from the binary
+    if std::env::args().any(|arg| arg == "--print-crd") {
+        println!("{}", serde_json::to_string_pretty(&template::AnnotationTemplate::crd())?);
+        return Ok(());
+    }
+
+    // Shows what the environment was actually parsed into, without connecting to the cluster. Config holds no secret values
+    // Warnings about values that fell back to their defaults go to stderr, leaving stdout to the config itself
+    if std::env::args().any(|arg| arg == "--print-config") {
+        tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+        println!("{:#?}", Config::from_env()?);
+        return Ok(());
+    }
 
+    telemetry::init()?;
+    let config = Config::from_env()?;
+    info!(config = ?config, "Loaded configuration");
+    info!("Using annotation domain {:?}", config.annotation_domain);
+    info!("Using placeholder prefix {:?} and suffix {:?} with style {:?}", config.placeholder_prefix, config.placeholder_suffix, config.placeholder_style);
+    info!("Reconciling up to {} resources concurrently", config.concurrency);
+    if config.dry_run {
+        info!("Dry run enabled, only the dryRunPlan annotation will be patched");
+    }
+
+    let client = connect(&config).await?;
+    let metrics = Metrics::new()?;
+
+    let ready = Arc::new(AtomicBool::new(false));
+    let server = server::run(config.metrics_port, metrics.registry.clone(), ready.clone());
+
+    // Admission reviews are answered by every replica, so there is nothing to elect a leader for
+    let leader = if config.leader_election && config.mode == Mode::Controller {
+        let identity = std::env::var("HOSTNAME").unwrap_or_else(|_| std::process::id().to_string());
+        info!("Using leader election with Lease {}/{}", config.lease_namespace, config.lease_name);
+        LeaderElection::new(client.clone(), &config.lease_namespace, &config.lease_name, &identity).start()
+    } else {
+        watch::channel(true).1
+    };
+
+    let paused = pause::start(config.paused, config.pause_file.clone());
+    let secret_cache = SecretCache::new(config.secret_cache_ttl);
+
+    let concurrency = config.concurrency;
     let context = Arc::new(OperatorContext {
-        client: client.clone()
+        client: client.clone(),
+        config,
+        reporter: Reporter {
+            controller: String::from("annotations-from-secret-applier"),
+            instance: std::env::var("HOSTNAME").ok(),
+        },
+        index: SecretIndex::with_gauge(metrics.tracked_ingresses.clone()),
+        secret_cache,
+        correlations: Correlations::default(),
+        metrics,
+        leader,
+        paused,
+        backoff: FailureBackoff::default(),
+        reconcile_slots: (concurrency > 0).then(|| Arc::new(Semaphore::new(concurrency.into()))),
     });
 
-    let ingress_api = Api::<Ingress>::all(client.clone());
+    if context.config.mode == Mode::Webhook {
+        let result = tokio::select! {
+            result = webhook::run(context.clone(), ready) => result,
+            result = server => result,
+            _ = shutdown_signal() => Ok(()),
+        };
+        let _ = tokio::task::spawn_blocking(telemetry::shutdown).await;
+        return result;
+    }
+
+    // Without a namespace restriction a single cluster-wide controller per kind is used
+    let namespaces = match &context.config.watch_namespaces {
+        Some(namespaces) => {
+            info!("Watching namespaces {:?}", namespaces);
+            namespaces.iter().cloned().map(Some).collect()
+        }
+        None => vec![None],
+    };
+
+    if let Some(label_selector) = &context.config.label_selector {
+        info!("Only processing resources matching label selector {:?}", label_selector.to_string());
+    }
+
+    let (shutdown_sender, shutdown) = watch::channel(false);
 
-    let controller = Controller::new(ingress_api, watcher::Config::default())
-        .run(reconcile, error_policy, context)
-        .for_each(|reconciliation| async move {
-            match reconciliation {
-                Ok(resource) => info!("Reconciled {:?}", resource),
-                Err(e) => error!("Reconciliation failed: {:?}", e),
+    let http_routes = gateway::is_installed(&client).await;
+
+    // Namespaces whose Secrets can be watched, for the others changes are only picked up by the periodic requeue
+    let mut secret_namespaces = Vec::new();
+    for namespace in &namespaces {
+        if access::can_watch_secrets(&client, namespace.as_deref()).await {
+            secret_namespaces.push(namespace.clone());
+        } else {
+            warn!("Not allowed to watch Secrets in {}, relying on the periodic requeue", namespace.as_deref().unwrap_or("the cluster"));
+        }
+    }
+
+    let secret_watch = SecretWatch::start(&client, &secret_namespaces, &context.secret_cache);
+
+    let mut stores = Vec::new();
+    let mut controllers = Vec::new();
+    for namespace in &namespaces {
+        let controller = build_controller::<Ingress>(&context, namespace, &secret_watch);
+        stores.push(store_ready(controller.store()));
+        controllers.push(run_controller(controller, namespace, reconcile_ingress, context.clone(), shutdown.clone()));
+
+        let controller = build_controller::<Service>(&context, namespace, &secret_watch);
+        stores.push(store_ready(controller.store()));
+        controllers.push(run_controller(controller, namespace, reconcile, context.clone(), shutdown.clone()));
+
+        if http_routes {
+            let controller = build_controller::<gateway::HttpRoute>(&context, namespace, &secret_watch);
+            stores.push(store_ready(controller.store()));
+            controllers.push(run_controller(controller, namespace, reconcile, context.clone(), shutdown.clone()));
+        }
+
+        // Requires the AnnotationTemplate CRD, so it is opt-in and left out of readiness
+        if context.config.annotation_templates {
+            let (controller, ingresses) = template::build_controller(&client, namespace, &watcher_config(&context.config), &secret_watch);
+            let reconcile = move |template, context| template::reconcile(template, context, ingresses.clone());
+            controllers.push(run_controller(controller, namespace, reconcile, context.clone(), shutdown.clone()));
+        }
+    }
+
+    tokio::spawn(wait_until_ready(client.clone(), stores, ready));
+
+    let mut controllers = pin!(futures::future::join_all(controllers));
+
+    let result = tokio::select! {
+        _ = &mut controllers => Ok(()),
+        result = server => result,
+        _ = shutdown_signal() => {
+            let shutdown_timeout = context.config.shutdown_timeout;
+            let metrics = &context.metrics;
+            info!("Shutting down, waiting up to {:?} for {} in-flight reconciles", shutdown_timeout, metrics.reconciles_in_flight_total());
+            shutdown_sender.send_replace(true);
+
+            if tokio::time::timeout(shutdown_timeout, controllers).await.is_err() {
+                warn!("Timed out waiting for in-flight reconciles, exiting with {} still running", metrics.reconciles_in_flight_total());
             }
-        });
+            Ok(())
+        }
+    };
 
-    controller.await;
-    Ok(())
+    // Flushing blocks on the exporter, which runs on this runtime
+    let _ = tokio::task::spawn_blocking(telemetry::shutdown).await;
+    result
+}
+
+// Without an explicit choice kube picks in-cluster or the current kubeconfig context, whichever it finds first
+async fn connect(config: &Config) -> Result<Client> {
+    let client_config = if config.in_cluster {
+        info!("Using the in-cluster configuration");
+        kube::Config::incluster()?
+    } else if let Some(context) = &config.kubeconfig_context {
+        info!("Using kubeconfig context {:?}", context);
+        let options = KubeConfigOptions {
+            context: Some(context.clone()),
+            ..KubeConfigOptions::default()
+        };
+        kube::Config::from_kubeconfig(&options).await?
+    } else {
+        return Ok(Client::try_default().await?);
+    };
+
+    Ok(Client::try_from(client_config)?)
+}
+
+async fn shutdown_signal() {
+    let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("Failed to install SIGTERM handler: {:?}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+    }
 }
 
-fn error_policy(_ingress: Arc<Ingress>, _error: &Error, _ctx: Arc<OperatorContext>) -> Action {
-    Action::requeue(Duration::from_secs(60)) // Requeue after 1 minute
+fn build_controller<K: Templated>(context: &Arc<OperatorContext>, namespace: &Option<String>, secret_watch: &SecretWatch) -> Controller<K> {
+    let client = &context.client;
+    let api = match namespace {
+        Some(namespace) => Api::<K>::namespaced(client.clone(), namespace),
+        None => Api::<K>::all(client.clone()),
+    };
+
+    let controller = Controller::new(api, watcher_config(&context.config));
+
+    // Secrets in any watched namespace can be referenced, so each controller hears about all of them
+    let store = controller.store();
+    let context = context.clone();
+    let scope = namespace.clone();
+    controller.watches_stream(secret_watch.subscribe(), move |secret| {
+        let objects = objects_for_secret(&store, &context.index, scope.as_deref(), &secret);
+        let correlation_id = correlation::for_secret(&secret);
+        for object in &objects {
+            if let Some(namespace) = &object.namespace {
+                context.correlations.propagate((K::kind(&()).to_string(), namespace.clone(), object.name.clone()), correlation_id.clone());
+            }
+        }
+        objects
+    })
+}
+
+// Ingresses and Services are filtered server-side by the configured label selector
+fn watcher_config(config: &Config) -> watcher::Config {
+    match &config.label_selector {
+        Some(label_selector) => watcher::Config::default().labels_from(label_selector),
+        None => watcher::Config::default(),
+    }
+}
+
+fn run_controller<K, R, F>(controller: Controller<K>, namespace: &Option<String>, mut reconciler: R, context: Arc<OperatorContext>, mut shutdown: watch::Receiver<bool>) -> BoxFuture<'static, ()>
+where
+    K: Templated,
+    R: FnMut(Arc<K>, Arc<OperatorContext>) -> F + Send + 'static,
+    F: TryFuture<Ok = Action, Error = OperatorError> + Send + 'static,
+{
+    let became_leader = switched_to(context.leader.clone(), true);
+    let resumed = switched_to(context.paused.clone(), false);
+
+    // A safety net on top of the per-object requeue, re-rendering everything in the cache even if requeues got lost
+    let full_resync = futures::stream::unfold(context.config.full_resync_interval, |interval| async move {
+        tokio::time::sleep(interval?).await;
+        info!("Starting full resync of all {} resources", K::kind(&()));
+        Some(((), interval))
+    });
+
+    // The initial list only reconciles what it sees as it streams in, this pass runs once the whole cache is there
+    // so every resource in scope is guaranteed a fresh reconcile after a restart
+    let store = controller.store();
+    let synced = futures::stream::once(async move {
+        if store.wait_until_ready().await.is_ok() {
+            info!("Cache synced, reconciling all {} resources", K::kind(&()));
+        }
+    });
+
+    // Stops taking new work once shutdown starts, while letting in-flight reconciles finish
+    let shutdown = async move {
+        let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+    };
+
+    // Caps parallel reconciles across all controllers so a cold start against a large cluster doesn't trip API server
+    // rate limits, each one waits for a slot before it counts as in flight. Counted so shutdown can tell how much work
+    // it is waiting for
+    let in_flight = context.metrics.reconciles_in_flight.with_label_values(&[K::kind(&()).as_ref(), namespace.as_deref().unwrap_or("")]);
+    let reconciler = move |resource, context: Arc<OperatorContext>| {
+        let in_flight = in_flight.clone();
+        let slots = context.reconcile_slots.clone();
+        let reconcile = reconciler(resource, context).into_future();
+        async move {
+            let _slot = match slots {
+                Some(slots) => slots.acquire_owned().await.ok(),
+                None => None,
+            };
+            in_flight.inc();
+            let result = reconcile.await;
+            in_flight.dec();
+            result
+        }
+    };
+
+    // The per-controller limit only bounds how many reconciles each controller has waiting for a slot, and debouncing
+    // coalesces bursts of events for the same object, e.g. a Secret churned by GitOps, into a single reconcile
+
+    let controller_config = controller::Config::default()
+        .concurrency(context.config.concurrency)
+        .debounce(context.config.debounce);
+
+    controller
+        .with_config(controller_config)
+        .reconcile_all_on(futures::stream::select(futures::stream::select(became_leader, resumed), futures::stream::select(synced, full_resync)))
+        .graceful_shutdown_on(shutdown)
+        .run(reconciler, error_policy, context.clone())
+        .for_each(move |reconciliation| {
+            // A success ends the failure streak, so the next failure starts backing off from scratch
+            if let Ok((resource, _)) = &reconciliation {
+                if let Some(namespace) = &resource.namespace {
+                    context.backoff.reset(&(K::kind(&()).to_string(), namespace.clone(), resource.name.clone()));
+                }
+            }
+
+            // Watch errors are the API server dropping or expiring the watch, the watcher backs off and relists
+            // on its own, so they are not reconcile failures
+            if let Err(controller::Error::QueueError(_)) = &reconciliation {
+                context.metrics.watch_restarts.with_label_values(&[&K::kind(&())]).inc();
+            }
+
+            async move {
+                match reconciliation {
+                    Ok(resource) => info!("Reconciled {:?}", resource),
+                    Err(controller::Error::QueueError(e)) => warn!("Watch failed, reconnecting: {}", e),
+                    Err(e) => error!("Reconciliation failed: {:?}", e),
+                }
+            }
+        })
+        .boxed()
+}
+
+// Yields every time the flag changes to `value`, e.g. this replica becoming the leader
+fn switched_to(flag: watch::Receiver<bool>, value: bool) -> impl futures::Stream<Item = ()> + Send + Sync {
+    futures::stream::unfold(flag, move |mut flag| async move {
+        loop {
+            flag.changed().await.ok()?;
+            if *flag.borrow() == value {
+                return Some(((), flag));
+            }
+        }
+    })
+}
+
+fn store_ready<K: Templated>(store: Store<K>) -> BoxFuture<'static, bool> {
+    async move { store.wait_until_ready().await.is_ok() }.boxed()
+}
+
+// Flags the operator as ready once every cache is populated and the API server answers
+async fn wait_until_ready(client: Client, stores: Vec<BoxFuture<'static, bool>>, ready: Arc<AtomicBool>) {
+    for store in stores {
+        if !store.await {
+            return;
+        }
+    }
+
+    loop {
+        match client.apiserver_version().await {
+            Ok(_) => break,
+            Err(e) => {
+                warn!("API server is not reachable yet: {:?}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+
+    info!("Caches synced, operator is ready");
+    ready.store(true, Ordering::Relaxed);
+}
+
+// Maps a changed (or deleted) Secret to every resource in this controller's scope referencing it
+fn objects_for_secret<K: Templated>(store: &Store<K>, index: &SecretIndex, scope: Option<&str>, secret: &Secret) -> Vec<ObjectRef<K>> {
+    let secret_key = match secret.metadata.namespace.as_ref() {
+        Some(namespace) => (namespace.clone(), secret.name_any()),
+        None => return Vec::new(),
+    };
+
+    let kind = K::kind(&());
+    let objects = index.lookup(&kind, &secret_key)
+        .into_iter()
+        .filter(|(namespace, _)| scope.is_none_or(|scope| scope == namespace))
+        .filter_map(|(namespace, name)| {
+            let object_ref = ObjectRef::<K>::new(&name).within(&namespace);
+            if store.get(&object_ref).is_none() {
+                // Deletions never reach reconcile, so stale entries are dropped once the cache has forgotten them
+                index.remove(&(kind.to_string(), namespace, name));
+                return None;
+            }
+            Some(object_ref)
+        })
+        .collect::<Vec<_>>();
+
+    if !objects.is_empty() {
+        debug!("Secret {}/{} changed, reconciling {} dependent {} resources", secret_key.0, secret_key.1, objects.len(), kind);
+    }
+    objects
+}
+
+fn object_key<K: Templated>(resource: &K) -> Option<ObjectKey> {
+    let namespace = resource.meta().namespace.clone()?;
+    let name = resource.meta().name.clone()?;
+    Some((K::kind(&()).to_string(), namespace, name))
+}
+
+// The annotation wins, the SECRET_NAME_LABEL label is only a fallback for policy engines that govern labels more
+// strictly. A label value can't hold `/` or `,`, so it always names one Secret in the resource's namespace
+fn secret_name<'a, K: Templated>(resource: &'a K, config: &Config) -> Option<&'a String> {
+    resource.annotations().get(&config.annotations.secret_name)
+        .or_else(|| config.secret_name_label.as_ref().and_then(|label| resource.labels().get(label)))
+}
+
+fn has_source<K: Templated>(resource: &K, config: &Config) -> bool {
+    let annotations = resource.annotations();
+    secret_name(resource, config).is_some()
+        || annotations.contains_key(&config.annotations.config_map_name)
+        || annotations.contains_key(&config.annotations.labels_secret_name)
+}
+
+fn referenced_secrets<K: Templated>(resource: &K, config: &Config) -> Vec<SecretKey> {
+    let namespace = match resource.meta().namespace.as_deref() {
+        Some(namespace) => namespace,
+        None => return Vec::new(),
+    };
+
+    let annotations = resource.annotations();
+    // Named Secrets are only read alongside an annotation source
+    let source = secret_name(resource, config);
+    let named_secrets = if source.is_some() || annotations.contains_key(&config.annotations.config_map_name) {
+        substitution::named_secrets(annotations, config)
+    } else {
+        Default::default()
+    };
+
+    source.into_iter()
+        .chain(annotations.get(&config.annotations.labels_secret_name))
+        .flat_map(|references| parse_secret_references(references, namespace).unwrap_or_default())
+        .map(|(namespace, name)| (String::from(namespace), String::from(name)))
+        .chain(named_secrets.into_iter().map(|name| (String::from(namespace), name)))
+        .collect()
+}
+
+fn error_policy<K: Templated>(resource: Arc<K>, error: &OperatorError, ctx: Arc<OperatorContext>) -> Action {
+    ctx.metrics.reconcile_failures.with_label_values(&[metrics::failure_reason(error)]).inc();
+    if let Some(namespace) = resource.meta().namespace.as_deref() {
+        ctx.metrics.record_reconcile(namespace, "error");
+    }
+
+    match error {
+        // Someone else just wrote the object, so a quick retry will likely see a consistent state
+        OperatorError::PatchConflict(_) => Action::requeue(Duration::from_secs(5)),
+        // Retrying won't help until someone fixes the RBAC rules, so this only checks back occasionally
+        OperatorError::Forbidden { .. } => {
+            error!("{}", error);
+            let recorder = ctx.recorder(resource.as_ref());
+            let note = error.to_string();
+            tokio::spawn(async move { publish_event(&recorder, EventType::Warning, "Forbidden", note).await });
+            Action::requeue(ctx.config.forbidden_requeue_interval)
+        }
+        error => {
+            let base = match error.category() {
+                // Quick retries, jittered so a blip of the API server doesn't bring every resource back at once
+                ErrorCategory::Transient => jitter(ctx.config.transient_error_requeue_interval, resource.as_ref(), ctx.config.requeue_jitter_percent),
+                // Nothing changes until the user fixes the resource, so there's no point in retrying soon
+                ErrorCategory::Permanent => return Action::requeue(ctx.config.permanent_error_requeue_interval),
+                ErrorCategory::Other => ctx.config.error_requeue_interval,
+            };
+            match object_key(resource.as_ref()) {
+                Some(object) => Action::requeue(ctx.backoff.next_delay(object, base)),
+                None => Action::requeue(base),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::ByteString;
+
+    fn secret(data: &[(&str, &[u8])]) -> Secret {
+        Secret {
+            data: Some(data.iter().map(|(key, value)| (String::from(*key), ByteString(value.to_vec()))).collect()),
+            ..Secret::default()
+        }
+    }
+
+    #[test]
+    fn secret_replacements_skip_invalid_utf8() {
+        let config = Config::for_tests();
+        let replacements = secret_replacements(&secret(&[("HOST", b"example.com"), ("BINARY", &[0xff, 0xfe])]), &config);
+        assert_eq!(replacements, BTreeMap::from([(String::from("HOST"), String::from("example.com"))]));
+    }
+
+    #[test]
+    fn secret_replacements_trim_values() {
+        let mut config = Config::for_tests();
+        config.trim_values = true;
+        let replacements = secret_replacements(&secret(&[("HOST", b"host\n")]), &config);
+        assert_eq!(replacements["HOST"], "host");
+    }
+
+    #[test]
+    fn jitter_clamps_huge_intervals() {
+        let ingress = Ingress { metadata: ObjectMeta { name: Some(String::from("a")), namespace: Some(String::from("default")), ..ObjectMeta::default() }, ..Ingress::default() };
+        assert!(jitter(Duration::MAX, &ingress, 100) <= MAX_REQUEUE_INTERVAL * 2);
+    }
+
+    #[test]
+    fn projected_size_leaves_out_cleared_annotations() {
+        let annotations = BTreeMap::from([(String::from("a"), String::from("1")), (String::from("big"), "x".repeat(100))]);
+        let updated = BTreeMap::from([(String::from("b"), String::from("2"))]);
+        assert_eq!(projected_annotation_size(&annotations, &updated, &["big"]), 4);
+    }
 }
@@ -0,0 +1,135 @@
+use anyhow::Result;
+use crate::error::OperatorError;
+use kube::Error;
+use prometheus::core::Collector;
+use prometheus::{exponential_buckets, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry};
+
+#[derive(Clone)]
+pub struct Metrics {
+    pub registry: Registry,
+    pub reconciles: IntCounter,
+    pub reconcile_failures: IntCounterVec,
+    pub reconcile_results: IntCounterVec,
+    pub patches: IntCounter,
+    pub dry_run_patches: IntCounter,
+    pub reconcile_duration: Histogram,
+    pub secret_fetch_duration: HistogramVec,
+    pub watch_restarts: IntCounterVec,
+    pub tracked_ingresses: IntGauge,
+    pub state_annotation_size: HistogramVec,
+    pub reconciles_in_flight: IntGaugeVec,
+    pub admission_reviews: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Metrics> {
+        let registry = Registry::new();
+
+        let reconciles = IntCounter::new("reconciles_total", "Total number of reconciles")?;
+        let reconcile_failures = IntCounterVec::new(
+            Opts::new("reconcile_failures_total", "Total number of failed reconciles"),
+            &["reason"],
+        )?;
+        let reconcile_results = IntCounterVec::new(
+            Opts::new("reconcile_total", "Total number of reconciles by namespace and result"),
+            &["namespace", "result"],
+        )?;
+        let patches = IntCounter::new("patches_total", "Total number of annotation patches applied")?;
+        let dry_run_patches = IntCounter::new("dry_run_patches_total", "Total number of patches skipped because of dry-run mode")?;
+        let reconcile_duration = Histogram::with_opts(
+            HistogramOpts::new("reconcile_duration_seconds", "Duration of reconciles in seconds"),
+        )?;
+        let secret_fetch_duration = HistogramVec::new(
+            HistogramOpts::new("secret_fetch_duration_seconds", "Duration of Secret fetches from the API server in seconds"),
+            &["namespace"],
+        )?;
+        let watch_restarts = IntCounterVec::new(
+            Opts::new("watch_restarts_total", "Total number of watch errors the watcher recovered from by reconnecting"),
+            &["kind"],
+        )?;
+        let tracked_ingresses = IntGauge::new("tracked_ingresses", "Number of Ingresses currently referencing a Secret")?;
+        // 256 bytes up to 256KiB, the limit for all annotations of an object together
+        let state_annotation_size = HistogramVec::new(
+            HistogramOpts::new("state_annotation_bytes", "Size of the state annotation in bytes")
+                .buckets(exponential_buckets(256.0, 2.0, 11)?),
+            &["namespace"],
+        )?;
+        // One controller runs per kind and watched namespace, "" when watching all of them. This is work that has
+        // started, not a queue depth: kube-runtime keeps its scheduler private, so reconciles waiting to start aren't counted
+        let reconciles_in_flight = IntGaugeVec::new(
+            Opts::new("reconciles_in_flight", "Number of reconciles currently running by controller kind and namespace"),
+            &["kind", "namespace"],
+        )?;
+        let admission_reviews = IntCounterVec::new(
+            Opts::new("admission_reviews_total", "Total number of admission reviews by result in webhook mode"),
+            &["result"],
+        )?;
+        // Always 1, the labels tell which build is running
+        let build_info = IntGaugeVec::new(Opts::new("build_info", "Version and commit of the running build"), &["version", "sha"])?;
+        build_info.with_label_values(&[env!("CARGO_PKG_VERSION"), env!("GIT_SHA")]).set(1);
+
+        registry.register(Box::new(reconciles.clone()))?;
+        registry.register(Box::new(reconcile_failures.clone()))?;
+        registry.register(Box::new(reconcile_results.clone()))?;
+        registry.register(Box::new(patches.clone()))?;
+        registry.register(Box::new(dry_run_patches.clone()))?;
+        registry.register(Box::new(reconcile_duration.clone()))?;
+        registry.register(Box::new(secret_fetch_duration.clone()))?;
+        registry.register(Box::new(watch_restarts.clone()))?;
+        registry.register(Box::new(tracked_ingresses.clone()))?;
+        registry.register(Box::new(state_annotation_size.clone()))?;
+        registry.register(Box::new(reconciles_in_flight.clone()))?;
+        registry.register(Box::new(admission_reviews.clone()))?;
+        registry.register(Box::new(build_info))?;
+
+        Ok(Metrics {
+            registry,
+            reconciles,
+            reconcile_failures,
+            reconcile_results,
+            patches,
+            dry_run_patches,
+            reconcile_duration,
+            secret_fetch_duration,
+            watch_restarts,
+            tracked_ingresses,
+            state_annotation_size,
+            reconciles_in_flight,
+            admission_reviews,
+        })
+    }
+
+    // `result` is one of patched, noop, error or secret_missing
+    pub fn record_reconcile(&self, namespace: &str, result: &str) {
+        self.reconcile_results.with_label_values(&[namespace, result]).inc();
+    }
+
+    // Summed over all controllers
+    pub fn reconciles_in_flight_total(&self) -> i64 {
+        self.reconciles_in_flight
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_gauge().get_value() as i64)
+            .sum()
+    }
+}
+
+// Keeps the `reason` label bounded to a handful of values
+pub fn failure_reason(error: &OperatorError) -> &'static str {
+    match error {
+        OperatorError::PatchConflict(_) => "conflict",
+        OperatorError::Serialization(_) => "serialization",
+        OperatorError::Forbidden { .. } => "forbidden",
+        OperatorError::Finalizer(_) => "finalizer",
+        OperatorError::KubeApi(Error::Api(response)) => match response.code {
+            403 => "forbidden",
+            404 => "not_found",
+            409 => "conflict",
+            _ => "api",
+        },
+        OperatorError::KubeApi(Error::SerdeError(_)) => "serialization",
+        OperatorError::KubeApi(_) => "transport",
+        OperatorError::Partial { source, .. } => failure_reason(source),
+    }
+}
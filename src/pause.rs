@@ -0,0 +1,68 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// A process can't see changes to its own environment, so PAUSED only sets the initial state while PAUSE_FILE, e.g. a
+// key of a mounted ConfigMap, is re-read periodically and on SIGHUP. While the file is missing PAUSED applies
+pub fn start(paused: bool, pause_file: Option<PathBuf>) -> watch::Receiver<bool> {
+    if paused {
+        info!("Reconciliation paused");
+    }
+
+    let (sender, receiver) = watch::channel(paused);
+    if let Some(pause_file) = pause_file {
+        tokio::spawn(watch_file(pause_file, paused, sender));
+    }
+    receiver
+}
+
+async fn watch_file(pause_file: PathBuf, default: bool, sender: watch::Sender<bool>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP, only polling {:?}: {:?}", pause_file, e);
+            None
+        }
+    };
+
+    let mut missing = false;
+    loop {
+        // Removing the ConfigMap key goes back to PAUSED, so PAUSED=true can't be lifted by a file that isn't there
+        let paused = match fs::read_to_string(&pause_file) {
+            Ok(content) => {
+                missing = false;
+                content.trim() == "true"
+            }
+            Err(e) => {
+                if !missing {
+                    info!("Can't read {:?}, falling back to PAUSED={}: {}", pause_file, default, e);
+                    missing = true;
+                }
+                default
+            }
+        };
+        if paused != *sender.borrow() {
+            if paused {
+                info!("Reconciliation paused");
+            } else {
+                info!("Reconciliation resumed");
+            }
+        }
+        sender.send_if_modified(|current| std::mem::replace(current, paused) != paused);
+
+        match &mut hangup {
+            Some(hangup) => {
+                tokio::select! {
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                    _ = hangup.recv() => info!("Received SIGHUP, re-reading {:?}", pause_file),
+                }
+            }
+            None => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    }
+}
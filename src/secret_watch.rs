@@ -0,0 +1,72 @@
+use crate::cache::SecretCache;
+use futures::{Stream, StreamExt};
+use k8s_openapi::api::core::v1::Secret;
+use kube::runtime::{watcher, WatchStreamExt};
+use kube::{Api, Client, ResourceExt};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+// Enough to absorb a relist of a busy namespace while the controllers catch up
+const CAPACITY: usize = 4096;
+
+// One Secret watch per namespace, fanned out to the controllers of every kind and namespace, so the number of watch
+// streams grows with the namespaces instead of with namespaces squared times kinds
+#[derive(Clone)]
+pub struct SecretWatch {
+    sender: broadcast::Sender<Secret>,
+}
+
+impl SecretWatch {
+    pub fn start(client: &Client, namespaces: &[Option<String>], secret_cache: &SecretCache) -> SecretWatch {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        for namespace in namespaces {
+            let api = match namespace {
+                Some(namespace) => Api::<Secret>::namespaced(client.clone(), namespace),
+                None => Api::<Secret>::all(client.clone()),
+            };
+            tokio::spawn(watch(api, sender.clone(), secret_cache.clone()));
+        }
+        SecretWatch { sender }
+    }
+
+    pub fn subscribe(&self) -> impl Stream<Item = Result<Secret, watcher::Error>> + Send + 'static {
+        futures::stream::unfold(self.sender.subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(secret) => return Some((Ok(secret), receiver)),
+                    Err(RecvError::Lagged(missed)) => warn!("Missed {} Secret events, their dependents wait for the periodic requeue", missed),
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
+
+async fn watch(api: Api<Secret>, sender: broadcast::Sender<Secret>, secret_cache: SecretCache) {
+    // Everything is reconciled once the caches have synced anyway, so only the initial list is left out. A relist
+    // after the watch expired is passed on, it may hold changes made while disconnected
+    let mut initial = true;
+    let mut events = watcher(api, watcher::Config::default()).default_backoff().boxed();
+    while let Some(event) = events.next().await {
+        let secret = match event {
+            Ok(watcher::Event::Apply(secret) | watcher::Event::Delete(secret)) => secret,
+            Ok(watcher::Event::InitApply(secret)) if !initial => secret,
+            Ok(watcher::Event::InitDone) => {
+                initial = false;
+                continue;
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Secret watch failed, reconnecting: {}", e);
+                continue;
+            }
+        };
+
+        if let Some(namespace) = &secret.metadata.namespace {
+            secret_cache.invalidate(&(namespace.clone(), secret.name_any()));
+        }
+        // Fails only without subscribers, e.g. while shutting down
+        let _ = sender.send(secret);
+    }
+}
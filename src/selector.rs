@@ -0,0 +1,69 @@
+use kube::core::{Expression, Selector};
+use std::collections::BTreeSet;
+
+// Parses the `kubectl -l` label selector syntax: `k=v`, `k==v`, `k!=v`, `k`, `!k`, `k in (a,b)` and `k notin (a,b)`
+pub fn parse(value: &str) -> Option<Selector> {
+    split_requirements(value)?
+        .into_iter()
+        .map(str::trim)
+        .filter(|requirement| !requirement.is_empty())
+        .map(parse_requirement)
+        .collect::<Option<Vec<_>>>()
+        .map(Selector::from_iter)
+}
+
+// Splits at the commas between requirements, but not at those inside a `(a,b)` value set
+fn split_requirements(value: &str) -> Option<Vec<&str>> {
+    let mut requirements = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return None,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                requirements.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return None;
+    }
+    requirements.push(&value[start..]);
+    Some(requirements)
+}
+
+fn parse_requirement(requirement: &str) -> Option<Expression> {
+    if let Some(key) = requirement.strip_prefix('!') {
+        return Some(Expression::DoesNotExist(parse_key(key)?));
+    }
+    if let Some((key, value)) = requirement.split_once("!=") {
+        return Some(Expression::NotEqual(parse_key(key)?, String::from(value.trim())));
+    }
+    if let Some((key, value)) = requirement.split_once("==").or_else(|| requirement.split_once('=')) {
+        return Some(Expression::Equal(parse_key(key)?, String::from(value.trim())));
+    }
+    if let Some((key, values)) = requirement.split_once(" notin ") {
+        return Some(Expression::NotIn(parse_key(key)?, parse_values(values)?));
+    }
+    if let Some((key, values)) = requirement.split_once(" in ") {
+        return Some(Expression::In(parse_key(key)?, parse_values(values)?));
+    }
+    Some(Expression::Exists(parse_key(requirement)?))
+}
+
+fn parse_key(key: &str) -> Option<String> {
+    let key = key.trim();
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(String::from(key))
+}
+
+fn parse_values(values: &str) -> Option<BTreeSet<String>> {
+    let values = values.trim().strip_prefix('(')?.strip_suffix(')')?;
+    Some(values.split(',').map(|value| String::from(value.trim())).collect())
+}
@@ -0,0 +1,49 @@
+use anyhow::Result;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{Encoder, Registry, TextEncoder};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::info;
+
+#[derive(Clone)]
+struct ServerState {
+    registry: Registry,
+    ready: Arc<AtomicBool>,
+}
+
+pub async fn run(port: u16, registry: Registry, ready: Arc<AtomicBool>) -> Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(ServerState { registry, ready });
+
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    info!("Serving metrics and health endpoints on port {}", port);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn metrics(State(state): State<ServerState>) -> Result<String, StatusCode> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&state.registry.gather(), &mut buffer)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    String::from_utf8(buffer).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+async fn readyz(State(state): State<ServerState>) -> StatusCode {
+    if state.ready.load(Ordering::Relaxed) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
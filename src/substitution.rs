@@ -0,0 +1,1083 @@
+use crate::config::{Config, PlaceholderStyle};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::{debug, info, warn};
+use crate::transforms;
+
+// Expands to every source value as a JSON object, unless a source has a key of that name
+const SECRET_AS_JSON: &str = "SECRET_AS_JSON";
+
+// Marks gzip compressed, base64 encoded state
+const COMPRESSED_STATE_PREFIX: &str = "gz:";
+
+pub struct AnnotationUpdate {
+    // Including the refreshed state and checksum annotations
+    pub annotations: BTreeMap<String, String>,
+    // Propagated annotations going back to not existing
+    pub removed: Vec<String>,
+}
+
+// Computes the annotations to patch, including the refreshed state annotation, or None when everything is up to date
+pub fn substitute_annotations(
+    annotations: &BTreeMap<String, String>,
+    replacements: &BTreeMap<String, String>,
+    config: &Config,
+) -> Result<Option<AnnotationUpdate>, serde_json::Error> {
+    let state_annotation = &config.annotations.state;
+    let checksum_annotation = &config.annotations.checksum;
+
+    // Corrupt state is recomputed from the current values, where already substituted ones hold no placeholders and
+    // are left alone, and always rewritten so the warning doesn't repeat on every reconcile
+    let (old_items, corrupt) = match parse_state(annotations.get(state_annotation)) {
+        Some(old_items) => (old_items, false),
+        None => (BTreeMap::new(), true),
+    };
+
+    let (mut updated_annotations, removed, state) = compute_annotations(annotations, replacements, &old_items, config);
+
+    // The checksum of what was last applied is stored next to the state, so a matching one plus no annotation
+    // changes means a no-op reconcile. Bumping resyncAt changes it too, which forces a full write
+    let resync_at = annotations.get(&config.annotations.resync_at).map(String::as_str);
+    let checksum = secret_checksum(replacements, resync_at);
+    let checksum_changed = annotations.get(checksum_annotation) != Some(&checksum);
+
+    if !corrupt && !checksum_changed && updated_annotations.is_empty() && removed.is_empty() && state == old_items {
+        return Ok(None);
+    }
+
+    updated_annotations.insert(state_annotation.clone(), serialize_state(&state, config)?);
+    updated_annotations.insert(checksum_annotation.clone(), checksum);
+    Ok(Some(AnnotationUpdate { annotations: updated_annotations, removed }))
+}
+
+pub struct LabelUpdate {
+    pub labels: BTreeMap<String, String>,
+    // Serialized value for the label state annotation
+    pub state: String,
+}
+
+// Pure core of the annotation substitution, returning the annotations that need to change, those to remove and the
+// new state
+pub fn compute_annotations(
+    current: &BTreeMap<String, String>,
+    replacements: &BTreeMap<String, String>,
+    prior_state: &State,
+    config: &Config,
+) -> (BTreeMap<String, String>, Vec<String>, State) {
+    let templatable = Templatable::new(current, config);
+
+    // Propagated annotations go through the same checks as any other annotation, so a mapping can't reach a
+    // protected or skipped one
+    let mut propagated = current.get(&config.annotations.propagate)
+        .map(|propagate| propagated_templates(propagate, config))
+        .unwrap_or_default();
+    propagated.retain(|key, _| {
+        let accepted = templatable.accepts(key);
+        if !accepted {
+            warn!("Not propagating to annotation {} as it may not be templated", key);
+        }
+        accepted
+    });
+
+    let values = current.iter()
+        .filter(|(key, value)| {
+            // Exact keys that are never rewritten, whatever they hold
+            if config.protected_annotations.contains(key) && contains_placeholder(value, replacements, config) {
+                warn!("Not substituting protected annotation {}", key);
+            }
+            templatable.accepts(key)
+        })
+        // Propagated annotations, including those that no longer are, are handled separately
+        .filter(|(key, _)| !propagated.contains_key(*key) && !matches!(prior_state.get(*key), Some(StateEntry::Propagated { .. })))
+        .map(|(key, value)| (key.as_str(), value.as_str()));
+
+    let max_length = max_value_length(current, config);
+    let (mut updated, mut state) = substitute(values, prior_state, replacements, max_length, config);
+    let (propagated_updates, removed, propagated_state) = propagate(current, &propagated, prior_state, replacements, max_length, config);
+    updated.extend(propagated_updates);
+    state.extend(propagated_state);
+    (updated, removed, state)
+}
+
+// Writes each propagated Secret value, remembering what the annotation held before. Once the Secret key or the
+// mapping is gone the annotation goes back to that, or is removed if it didn't exist
+fn propagate(
+    current: &BTreeMap<String, String>,
+    propagated: &BTreeMap<String, String>,
+    prior_state: &State,
+    replacements: &BTreeMap<String, String>,
+    max_length: Option<usize>,
+    config: &Config,
+) -> (BTreeMap<String, String>, Vec<String>, State) {
+    let mut updated = BTreeMap::new();
+    let mut removed = Vec::new();
+    let mut state = BTreeMap::new();
+
+    for (key, template) in propagated {
+        let original = match prior_state.get(key) {
+            Some(StateEntry::Propagated { original, .. }) => original.clone(),
+            // State from before originals were kept only holds the placeholder, what the annotation held is lost
+            Some(entry) if entry.template() == template => None,
+            _ => current.get(key).cloned(),
+        };
+
+        // The placeholder is left as it is when the Secret lacks the key
+        let value = current.get(key);
+        match render_complete(template, replacements, config).filter(|replaced_value| replaced_value != template) {
+            Some(replaced_value) if max_length.is_some_and(|max_length| replaced_value.len() > max_length) && value != Some(&replaced_value) => {
+                warn!("Propagated value of {} is {} bytes, over the limit, keeping its current value", key, replaced_value.len());
+                state.insert(key.clone(), StateEntry::propagated(template, original, value.map_or("", String::as_str)));
+            }
+            Some(replaced_value) => {
+                state.insert(key.clone(), StateEntry::propagated(template, original, &replaced_value));
+                if value != Some(&replaced_value) {
+                    updated.insert(key.clone(), replaced_value);
+                }
+            }
+            None => {
+                if prior_state.contains_key(key) {
+                    info!("{} could not be propagated, restoring its previous value", key);
+                }
+                restore(key, original.as_ref(), current, &mut updated, &mut removed);
+            }
+        }
+    }
+
+    // Only while the annotation still holds what was propagated, a user edit takes over
+    for (key, entry) in prior_state {
+        if let StateEntry::Propagated { original, .. } = entry {
+            if !propagated.contains_key(key) && current.get(key).is_some_and(|value| entry.written(value, replacements, config)) {
+                info!("{} is no longer propagated, restoring its previous value", key);
+                restore(key, original.as_ref(), current, &mut updated, &mut removed);
+            }
+        }
+    }
+
+    (updated, removed, state)
+}
+
+fn restore(key: &str, original: Option<&String>, current: &BTreeMap<String, String>, updated: &mut BTreeMap<String, String>, removed: &mut Vec<String>) {
+    match original {
+        Some(original) if current.get(key) != Some(original) => {
+            updated.insert(String::from(key), original.clone());
+        }
+        None if current.contains_key(key) => removed.push(String::from(key)),
+        _ => {}
+    }
+}
+
+// Which annotations may be templated at all. The operator's own annotations, skipped and protected ones, and
+// anything outside the annotationKeys and templatedKeys lists are never touched
+struct Templatable<'a> {
+    allowed_keys: Option<Vec<&'a str>>,
+    templated_keys: Option<Vec<&'a str>>,
+    config: &'a Config,
+}
+
+impl<'a> Templatable<'a> {
+    fn new(annotations: &'a BTreeMap<String, String>, config: &'a Config) -> Templatable<'a> {
+        Templatable {
+            // When present, only the listed annotations are considered, so stray placeholders elsewhere are left alone
+            allowed_keys: listed_keys(annotations, &config.annotations.annotation_keys),
+            // The explicit opt-in; with both present an annotation has to be listed in each of them
+            templated_keys: listed_keys(annotations, &config.annotations.templated_keys),
+            config,
+        }
+    }
+
+    fn accepts(&self, key: &str) -> bool {
+        let config = self.config;
+        let keys = &config.annotations;
+        let own = [&keys.state, &keys.checksum, &keys.label_state, &keys.label_templates, &keys.dry_run_plan, &keys.spec_templates, &keys.spec_state];
+
+        let skipped = (config.skip_last_applied && key == "kubectl.kubernetes.io/last-applied-configuration")
+            || own.iter().any(|own| *own == key)
+            || config.skip_annotation_prefixes.iter().any(|prefix| key.starts_with(prefix.as_str()))
+            || config.protected_annotations.iter().any(|protected| protected == key);
+
+        !skipped
+            && self.allowed_keys.as_ref().is_none_or(|allowed_keys| allowed_keys.contains(&key))
+            && self.templated_keys.as_ref().is_none_or(|templated_keys| templated_keys.contains(&key))
+    }
+}
+
+// The maxValueLength annotation overrides MAX_VALUE_LENGTH for a single resource, invalid values fall back to it
+fn max_value_length(annotations: &BTreeMap<String, String>, config: &Config) -> Option<usize> {
+    match annotations.get(&config.annotations.max_value_length) {
+        Some(length) => match length.trim().parse::<usize>() {
+            Ok(length) if length > 0 => Some(length),
+            _ => {
+                warn!("Ignoring invalid {} {:?}, using the default limit", config.annotations.max_value_length, length);
+                config.max_value_length
+            }
+        },
+        None => config.max_value_length,
+    }
+}
+
+fn listed_keys<'a>(annotations: &'a BTreeMap<String, String>, key: &str) -> Option<Vec<&'a str>> {
+    annotations.get(key).map(|keys| keys.split(',').map(str::trim).filter(|key| !key.is_empty()).collect())
+}
+
+// Parses `KEY=annotation,annotation;OTHER=annotation` into the placeholder each listed annotation is set to
+fn propagated_templates(propagate: &str, config: &Config) -> BTreeMap<String, String> {
+    let mut templates = BTreeMap::new();
+    for mapping in propagate.split(';').map(str::trim).filter(|mapping| !mapping.is_empty()) {
+        let (secret_key, annotation_keys) = match mapping.split_once('=') {
+            Some(mapping) => mapping,
+            None => {
+                warn!("Ignoring propagate mapping {:?}, expected KEY=annotation,annotation", mapping);
+                continue;
+            }
+        };
+
+        let delimiter = &delimiters(config)[0];
+        let template = format!("{}{}{}", delimiter.prefix, secret_key.trim(), delimiter.suffix);
+        for annotation_key in annotation_keys.split(',').map(str::trim).filter(|key| !key.is_empty()) {
+            templates.insert(String::from(annotation_key), template.clone());
+        }
+    }
+    templates
+}
+
+// Computes the labels to patch plus the refreshed label state annotation, or None when everything is up to date.
+// Placeholders are never valid label values, so the templates come from the labelTemplates annotation, a JSON object
+// of label keys to templates, and the state only records what was rendered. A label whose template is removed keeps
+// its last value and is left alone from then on
+pub fn substitute_labels(
+    labels: &BTreeMap<String, String>,
+    annotations: &BTreeMap<String, String>,
+    replacements: &BTreeMap<String, String>,
+    config: &Config,
+) -> Result<Option<LabelUpdate>, serde_json::Error> {
+    let templates = match annotations.get(&config.annotations.label_templates) {
+        Some(templates) => match serde_json::from_str::<BTreeMap<String, String>>(templates) {
+            Ok(templates) => templates,
+            Err(e) => {
+                warn!("Ignoring {} as it is not a JSON object of label keys to templates: {}", config.annotations.label_templates, e);
+                return Ok(None);
+            }
+        },
+        None => BTreeMap::new(),
+    };
+
+    let (old_items, corrupt) = match parse_state(annotations.get(&config.annotations.label_state)) {
+        Some(old_items) => (old_items, false),
+        None => (BTreeMap::new(), true),
+    };
+
+    let values = templates.iter().map(|(key, template)| (key.as_str(), template.as_str()));
+
+    // Label values are capped at 63 characters anyway, which is checked separately
+    let (mut updated_labels, state) = substitute(values, &old_items, replacements, None, config);
+
+    // Those were compared against their template rather than what the resource holds. A rejected label value would
+    // fail the whole patch, so it is dropped here and retried on the next change
+    updated_labels.retain(|key, value| labels.get(key) != Some(value));
+    updated_labels.retain(|key, value| {
+        let valid = is_valid_label_value(value);
+        if !valid {
+            warn!("Skipping label {} as {:?} is not a valid label value", key, value);
+        }
+        valid
+    });
+
+    if !corrupt && updated_labels.is_empty() && state == old_items {
+        return Ok(None);
+    }
+
+    Ok(Some(LabelUpdate { labels: updated_labels, state: serialize_state(&state, config)? }))
+}
+
+pub struct SpecUpdate {
+    // JSON pointer within the spec to its new value
+    pub values: BTreeMap<String, String>,
+    // Serialized value for the spec state annotation, None once nothing in the spec is templated anymore
+    pub state: Option<String>,
+}
+
+// Renders the specTemplates annotation, a JSON object of JSON pointers within the spec to templates, into the string
+// fields the pointers resolve to. The state remembers what each field held before it was first templated, so a
+// field that is no longer templated goes back to it. None when everything is up to date
+//
+// `secret_name_fields` are pointers to fields holding Secret names, e.g. an Ingress' TLS secretName, which may hold
+// placeholders themselves. Those are remembered in the state like templated annotations are, and a substituted name
+// that is not a valid Secret name is never written
+pub fn substitute_spec(
+    spec: &serde_json::Value,
+    secret_name_fields: &[String],
+    annotations: &BTreeMap<String, String>,
+    replacements: &BTreeMap<String, String>,
+    config: &Config,
+) -> Result<Option<SpecUpdate>, serde_json::Error> {
+    let templates = match annotations.get(&config.annotations.spec_templates) {
+        Some(templates) => match serde_json::from_str::<BTreeMap<String, String>>(templates) {
+            Ok(templates) => templates,
+            Err(e) => {
+                warn!("Ignoring {} as it is not a JSON object of JSON pointers to templates: {}", config.annotations.spec_templates, e);
+                return Ok(None);
+            }
+        },
+        None => BTreeMap::new(),
+    };
+
+    let (old_items, corrupt) = match parse_state(annotations.get(&config.annotations.spec_state)) {
+        Some(old_items) => (old_items, false),
+        None => (BTreeMap::new(), true),
+    };
+
+    let mut values = BTreeMap::new();
+    let mut state = BTreeMap::new();
+    for (pointer, template) in &templates {
+        let current = match spec.pointer(pointer) {
+            Some(serde_json::Value::String(current)) => current,
+            Some(_) => {
+                warn!("Skipping spec template for {:?} as it does not point at a string field", pointer);
+                continue;
+            }
+            None => {
+                warn!("Skipping spec template for {:?} as it does not point at an existing field", pointer);
+                continue;
+            }
+        };
+
+        let original = old_items.get(pointer).map_or(current.as_str(), StateEntry::template);
+        state.insert(pointer.clone(), StateEntry::Template(String::from(original)));
+        match render_complete(template, replacements, config) {
+            Some(value) if value != *current => {
+                values.insert(pointer.clone(), value);
+            }
+            Some(_) => {}
+            None => warn!("Spec field {:?} could not be fully substituted, keeping its current value", pointer),
+        }
+    }
+
+    for pointer in secret_name_fields.iter().filter(|pointer| !templates.contains_key(*pointer)) {
+        let current = match spec.pointer(pointer) {
+            Some(serde_json::Value::String(current)) => current,
+            _ => continue,
+        };
+
+        // The remembered template only applies while the field still holds what was written from it, a user edit
+        // takes over and is left alone from then on
+        let template = match old_items.get(pointer) {
+            Some(entry) if entry.written(current, replacements, config) => entry.template(),
+            _ if contains_placeholder(current, replacements, config) => current.as_str(),
+            _ => continue,
+        };
+
+        let written = match render_complete(template, replacements, config) {
+            Some(value) if !is_valid_secret_name(&value) => {
+                warn!("Skipping spec field {:?} as {:?} is not a valid Secret name", pointer, value);
+                current.clone()
+            }
+            Some(value) => {
+                if value != *current {
+                    values.insert(pointer.clone(), value.clone());
+                }
+                value
+            }
+            None => {
+                warn!("Spec field {:?} could not be fully substituted, keeping its current value", pointer);
+                current.clone()
+            }
+        };
+        state.insert(pointer.clone(), StateEntry::applied(template, &written));
+    }
+
+    // Secret name fields dropped from the state were taken over by the user, only spec templates are reverted
+    let reverted = old_items.iter()
+        .filter(|(pointer, _)| !state.contains_key(*pointer) && !secret_name_fields.contains(*pointer))
+        .map(|(pointer, entry)| (pointer, entry.template()));
+    for (pointer, original) in reverted {
+        if let Some(serde_json::Value::String(current)) = spec.pointer(pointer) {
+            if current != original {
+                info!("Reverting spec field {:?} to its original value", pointer);
+                values.insert(pointer.clone(), String::from(original));
+            }
+        }
+    }
+
+    if !corrupt && values.is_empty() && state == old_items {
+        return Ok(None);
+    }
+
+    let state = if state.is_empty() { None } else { Some(serialize_state(&state, config)?) };
+    Ok(Some(SpecUpdate { values, state }))
+}
+
+// Names of the Secrets referenced by `$name/KEY$` placeholders, in current values as well as remembered templates.
+// Only annotations compute_annotations would template count, plus spec templates and propagate mappings, which are
+// rendered with the same values. `:` already starts a default, and Secret keys can't contain `/`, so these never
+// clash with a plain key
+pub fn named_secrets(annotations: &BTreeMap<String, String>, config: &Config) -> BTreeSet<String> {
+    let templatable = Templatable::new(annotations, config);
+    let state = parse_state(annotations.get(&config.annotations.state)).unwrap_or_default();
+    let propagated = annotations.get(&config.annotations.propagate)
+        .map(|propagate| propagated_templates(propagate, config))
+        .unwrap_or_default();
+    let templates = annotations.iter()
+        .filter(|(key, _)| templatable.accepts(key))
+        .map(|(_, value)| value.as_str())
+        .chain(annotations.get(&config.annotations.spec_templates).map(String::as_str))
+        .chain(propagated.iter().filter(|(key, _)| templatable.accepts(key)).map(|(_, template)| template.as_str()))
+        .chain(state.values().map(StateEntry::template));
+
+    let names = RefCell::new(BTreeSet::new());
+    for template in templates {
+        replace_placeholders(template, config, |placeholder| {
+            if let Some((name, key)) = placeholder.key.split_once('/') {
+                if !name.is_empty() && !key.is_empty() {
+                    names.borrow_mut().insert(String::from(name));
+                }
+            }
+            None
+        });
+    }
+    names.into_inner()
+}
+
+// Remembered values by annotation, label or JSON pointer
+pub type State = BTreeMap<String, StateEntry>;
+
+// Usually a template, for spec templates the field's original value. `applied` is a hash of the value last written
+// from the template, which tells the operator's own writes apart from user edits. Without one it is stored as a plain
+// string, which is also how state from before hashes existed reads. A propagated annotation keeps its placeholder
+// next to what it held before, None when it didn't exist
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StateEntry {
+    Applied { template: String, applied: String },
+    Propagated { propagated: String, original: Option<String>, applied: String },
+    Template(String),
+}
+
+impl StateEntry {
+    fn applied(template: &str, value: &str) -> StateEntry {
+        StateEntry::Applied { template: String::from(template), applied: value_hash(value) }
+    }
+
+    fn propagated(template: &str, original: Option<String>, value: &str) -> StateEntry {
+        StateEntry::Propagated { propagated: String::from(template), original, applied: value_hash(value) }
+    }
+
+    pub fn template(&self) -> &str {
+        match self {
+            StateEntry::Applied { template, .. } | StateEntry::Template(template) => template,
+            StateEntry::Propagated { propagated, .. } => propagated,
+        }
+    }
+
+    // What a revert writes back, None removes the key
+    pub fn into_original(self) -> Option<String> {
+        match self {
+            StateEntry::Applied { template, .. } | StateEntry::Template(template) => Some(template),
+            StateEntry::Propagated { original, .. } => original,
+        }
+    }
+
+    // Whether `value` is still what was written from the template. Without a hash all that can be told is that a
+    // value holding no placeholders was substituted, by the operator or not
+    fn written(&self, value: &str, replacements: &BTreeMap<String, String>, config: &Config) -> bool {
+        match self {
+            StateEntry::Applied { applied, .. } | StateEntry::Propagated { applied, .. } => *applied == value_hash(value),
+            StateEntry::Template(_) => render(value, replacements, config) == value,
+        }
+    }
+}
+
+// Truncated, as it only has to tell a written value apart from an edited one
+fn value_hash(value: &str) -> String {
+    Sha256::digest(value.as_bytes()).iter().take(8).map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Reads both plain JSON and `gz:` prefixed state, so toggling COMPRESS_STATE never loses track of templates. None
+// when the state is present but corrupt, e.g. after a manual edit
+pub fn parse_state(state: Option<&String>) -> Option<State> {
+    let state = match state {
+        Some(state) => state,
+        None => return Some(BTreeMap::new()),
+    };
+
+    let json = match state.strip_prefix(COMPRESSED_STATE_PREFIX) {
+        Some(compressed) => match decompress(compressed) {
+            Some(json) => json,
+            None => {
+                warn!("Ignoring compressed state that could not be decompressed");
+                return None;
+            }
+        },
+        None => state.clone(),
+    };
+
+    match serde_json::from_str(&json) {
+        Ok(state) => Some(state),
+        Err(e) => {
+            warn!("Ignoring state that is not a JSON object of strings: {}", e);
+            None
+        }
+    }
+}
+
+fn serialize_state(state: &State, config: &Config) -> Result<String, serde_json::Error> {
+    let json = serde_json::to_string(state)?;
+    if !config.compress_state {
+        return Ok(json);
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    match encoder.write_all(json.as_bytes()).and_then(|_| encoder.finish()) {
+        Ok(compressed) => Ok(format!("{}{}", COMPRESSED_STATE_PREFIX, STANDARD.encode(compressed))),
+        Err(e) => {
+            warn!("Failed to compress state, storing it uncompressed: {:?}", e);
+            Ok(json)
+        }
+    }
+}
+
+fn decompress(compressed: &str) -> Option<String> {
+    let bytes = STANDARD.decode(compressed).ok()?;
+    let mut json = String::new();
+    GzDecoder::new(bytes.as_slice()).read_to_string(&mut json).ok()?;
+    Some(json)
+}
+
+// Renders every value against its remembered template, returning the changed values and the new state
+fn substitute<'a>(
+    values: impl Iterator<Item = (&'a str, &'a str)>,
+    old_items: &State,
+    replacements: &BTreeMap<String, String>,
+    max_length: Option<usize>,
+    config: &Config,
+) -> (BTreeMap<String, String>, State) {
+    let mut updated = BTreeMap::new();
+
+    // Rebuilt from scratch so it only ever holds values that are currently templated
+    let mut state = BTreeMap::new();
+
+    for (key, value) in values {
+        // The remembered template only applies while the value is still what was written from it, so a user edit,
+        // whether a literal or a new template, takes over
+        let original_value = match old_items.get(key) {
+            Some(entry) if entry.written(value, replacements, config) => entry.template(),
+            // Only a value with a placeholder that resolves is a template, anything else is left exactly as it is,
+            // escapes included, so e.g. `$$pid` in an unrelated nginx snippet is never unescaped
+            _ if contains_placeholder(value, replacements, config) => value,
+            _ => continue,
+        };
+
+        // All placeholders are substituted into one working string so none of them overwrite each other
+        let replaced_value = match render_complete(original_value, replacements, config) {
+            Some(replaced_value) => replaced_value,
+            None => {
+                warn!("{} could not be fully substituted, keeping its current value", key);
+                state.insert(String::from(key), StateEntry::applied(original_value, value));
+                continue;
+            }
+        };
+        // Downstream controllers may silently truncate long values, so those are never written in the first place
+        if let Some(max_length) = max_length.filter(|max_length| replaced_value.len() > *max_length && replaced_value != value) {
+            warn!("Substituted value of {} is {} bytes, over the limit of {}, keeping its current value", key, replaced_value.len(), max_length);
+            state.insert(String::from(key), StateEntry::applied(original_value, value));
+            continue;
+        }
+        if replaced_value != original_value {
+            state.insert(String::from(key), StateEntry::applied(original_value, &replaced_value));
+            if replaced_value != value {
+                updated.insert(String::from(key), replaced_value);
+            }
+        } else if value != original_value {
+            // The template no longer references any available key, so it is rolled back to the original
+            info!("Reverting {} to its original value", key);
+            updated.insert(String::from(key), String::from(original_value));
+        }
+    }
+
+    (updated, state)
+}
+
+// At most 63 characters, alphanumeric at both ends with `-`, `_` and `.` in between, or empty
+fn is_valid_label_value(value: &str) -> bool {
+    if value.is_empty() {
+        return true;
+    }
+
+    value.len() <= 63
+        && value.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && value.ends_with(|c: char| c.is_ascii_alphanumeric())
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+// A DNS subdomain: at most 253 characters of lowercase alphanumerics, `-` and `.`, alphanumeric at both ends
+fn is_valid_secret_name(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 253
+        && value.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && value.ends_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && value.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.')
+}
+
+// Stable SHA-256 over the sorted source key/values, so a changed Secret can be told apart from a no-op reconcile
+pub fn secret_checksum(replacements: &BTreeMap<String, String>, resync_at: Option<&str>) -> String {
+    let mut hasher = Sha256::new();
+    for (key, value) in replacements {
+        // Length prefixes keep `a=bc` and `ab=c` from hashing the same
+        hasher.update((key.len() as u64).to_le_bytes());
+        hasher.update(key.as_bytes());
+        hasher.update((value.len() as u64).to_le_bytes());
+        hasher.update(value.as_bytes());
+    }
+    // Appended only when set, so checksums stored before resyncAt existed stay valid
+    if let Some(resync_at) = resync_at {
+        hasher.update(b"resyncAt");
+        hasher.update(resync_at.as_bytes());
+    }
+
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Substitutes every known placeholder, falling back to `$key:default$` defaults for missing keys
+pub fn render(template: &str, replacements: &BTreeMap<String, String>, config: &Config) -> String {
+    expand(template, replacements, config, config.substitution_passes).0
+}
+
+// Like `render`, but None when a substituted value still holds placeholders after the configured number of passes or
+// an empty value was skipped, so a partially expanded value is never written
+pub fn render_complete(template: &str, replacements: &BTreeMap<String, String>, config: &Config) -> Option<String> {
+    let (value, complete) = expand(template, replacements, config, config.substitution_passes);
+    complete.then_some(value)
+}
+
+// Only substituted values are expanded again, so escapes in the template itself are unescaped exactly once
+fn expand(template: &str, replacements: &BTreeMap<String, String>, config: &Config, passes: u32) -> (String, bool) {
+    let complete = Cell::new(true);
+    let value = replace_placeholders(template, config, |placeholder| {
+        let mut value = resolve(placeholder, replacements, config)?;
+
+        // Downstream controllers tend to misread a blank value silently, e.g. as a zero proxy-body-size
+        if value.trim().is_empty() {
+            if config.skip_empty {
+                debug!("Not substituting empty value for placeholder {:?}", placeholder.key);
+                complete.set(false);
+                return None;
+            }
+            warn!("Substituting an empty value for placeholder {:?}", placeholder.key);
+        }
+
+        if passes > 1 {
+            let (expanded, expanded_complete) = expand(&value, replacements, config, passes - 1);
+            value = expanded;
+            complete.set(complete.get() && expanded_complete);
+        } else if contains_placeholder(&value, replacements, config) {
+            complete.set(false);
+        }
+
+        match placeholder.transform {
+            Some(name) => {
+                let transformed = transforms::apply(name, &value);
+                if transformed.is_none() {
+                    warn!("Unknown transform {:?} for placeholder {:?}, leaving it untouched", name, placeholder.key);
+                }
+                transformed
+            }
+            None => Some(value),
+        }
+    });
+    (value, complete.get())
+}
+
+// The raw value for a placeholder, before any transform
+fn resolve(placeholder: &Placeholder, replacements: &BTreeMap<String, String>, config: &Config) -> Option<String> {
+    let value = lookup(replacements, placeholder.key, config).cloned()
+        .or_else(|| (placeholder.key == SECRET_AS_JSON).then(|| secret_as_json(replacements)));
+
+    match (value, placeholder.pointer) {
+        (Some(value), Some(pointer)) => json_pointer(&value, pointer).or_else(|| {
+            warn!("JSON pointer {:?} does not resolve in Secret key {:?}, leaving it untouched", pointer, placeholder.key);
+            None
+        }),
+        (Some(value), None) => Some(value),
+        (None, _) => placeholder.default.map(String::from),
+    }
+}
+
+// All source values as one JSON object, for consumers that parse their configuration from a single annotation.
+// Values of named Secrets are left out, and a secretKeys annotation narrows it down like any other placeholder
+fn secret_as_json(replacements: &BTreeMap<String, String>) -> String {
+    let object = replacements.iter()
+        .filter(|(key, _)| !key.contains('/'))
+        .map(|(key, value)| (key.clone(), serde_json::Value::String(value.clone())))
+        .collect::<serde_json::Map<_, _>>();
+    serde_json::Value::Object(object).to_string()
+}
+
+// Whether another pass would substitute anything, escapes alone don't count
+fn contains_placeholder(value: &str, replacements: &BTreeMap<String, String>, config: &Config) -> bool {
+    let found = Cell::new(false);
+    replace_placeholders(value, config, |placeholder| {
+        if resolve(placeholder, replacements, config).is_some() {
+            found.set(true);
+        }
+        None
+    });
+    found.get()
+}
+
+// An exact match always wins, otherwise with CASE_INSENSITIVE_KEYS the first key in sorted order that only differs by
+// case is used, so `DATABASE_HOST` is picked over `Database_Host`
+fn lookup<'a>(replacements: &'a BTreeMap<String, String>, key: &str, config: &Config) -> Option<&'a String> {
+    if let Some(value) = replacements.get(key) {
+        return Some(value);
+    }
+    if !config.case_insensitive_keys {
+        return None;
+    }
+
+    let mut matches = replacements.iter().filter(|(candidate, _)| candidate.to_lowercase() == key.to_lowercase());
+    let (matched_key, value) = matches.next()?;
+    let ambiguous = matches.map(|(candidate, _)| candidate.as_str()).collect::<Vec<_>>();
+    if !ambiguous.is_empty() {
+        warn!("Placeholder {:?} matches Secret keys {:?} and {:?} ignoring case, using {:?}", key, matched_key, ambiguous, matched_key);
+    }
+    Some(value)
+}
+
+// Looks up `pointer` in a JSON-valued Secret key, using string values as they are and serializing anything else
+fn json_pointer(value: &str, pointer: &str) -> Option<String> {
+    let document: serde_json::Value = serde_json::from_str(value).ok()?;
+    match document.pointer(pointer)? {
+        serde_json::Value::String(value) => Some(value.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+// A `$key#/pointer|transform:default$` placeholder, where the JSON pointer, the transform and the default are optional
+struct Placeholder<'a> {
+    key: &'a str,
+    pointer: Option<&'a str>,
+    transform: Option<&'a str>,
+    default: Option<&'a str>,
+}
+
+impl<'a> Placeholder<'a> {
+    // The default is everything after the first `:`, so it may contain colons itself. None unless the key looks like
+    // a Secret key, optionally prefixed by a Secret name, so a stray prefix in free-form text such as an nginx snippet
+    // never opens a placeholder
+    fn parse(inner: &'a str) -> Option<Placeholder<'a>> {
+        let (reference, default) = match inner.split_once(':') {
+            Some((reference, default)) => (reference, Some(default)),
+            None => (inner, None),
+        };
+        let (key, transform) = match reference.split_once('|') {
+            Some((key, transform)) => (key, Some(transform)),
+            None => (reference, None),
+        };
+        let (key, pointer) = match key.split_once('#') {
+            Some((key, pointer)) => (key, Some(pointer)),
+            None => (key, None),
+        };
+
+        let valid_key = |key: &str| !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+        let valid = match key.split_once('/') {
+            Some((name, key)) => valid_key(name) && valid_key(key),
+            None => valid_key(key),
+        };
+        valid.then_some(Placeholder { key, pointer, transform, default })
+    }
+}
+
+// Opening and closing strings of one placeholder style, plus the escape written for a literal opening
+struct Delimiter<'a> {
+    prefix: &'a str,
+    suffix: &'a str,
+    escape: String,
+}
+
+fn delimiters(config: &Config) -> Vec<Delimiter<'_>> {
+    let mut delimiters = Vec::new();
+    if config.placeholder_style != PlaceholderStyle::Brace {
+        let prefix = config.placeholder_prefix.as_str();
+        delimiters.push(Delimiter { prefix, suffix: config.placeholder_suffix.as_str(), escape: prefix.repeat(2) });
+    }
+    if config.placeholder_style != PlaceholderStyle::DollarPair {
+        delimiters.push(Delimiter { prefix: "${", suffix: "}", escape: String::from("$${") });
+    }
+    delimiters
+}
+
+// Replaces every placeholder that `resolve` returns a value for and unescapes doubled prefixes, values are inserted
+// as they are so an escape inside a Secret value stays untouched. With several styles the earliest and then longest
+// match wins, so `${KEY}` is never also read as a `$...$` placeholder
+fn replace_placeholders<F>(template: &str, config: &Config, resolve: F) -> String
+where
+    F: Fn(&Placeholder) -> Option<String>,
+{
+    let delimiters = delimiters(config);
+
+    let mut result = String::new();
+    let mut rest = template;
+    loop {
+        let next = delimiters.iter()
+            .flat_map(|delimiter| [
+                (rest.find(delimiter.escape.as_str()), delimiter.escape.len(), delimiter, true),
+                (rest.find(delimiter.prefix), delimiter.prefix.len(), delimiter, false),
+            ])
+            .filter_map(|(start, len, delimiter, escape)| Some((start?, len, delimiter, escape)))
+            .min_by_key(|(start, len, _, _)| (*start, Reverse(*len)));
+
+        let (start, len, delimiter, escape) = match next {
+            Some(next) => next,
+            None => break,
+        };
+
+        // A doubled prefix is a literal one that never opens or closes a placeholder, e.g. `$$host` for nginx's `$host`
+        if escape {
+            result.push_str(&rest[..start]);
+            result.push_str(delimiter.prefix);
+            rest = &rest[start + len..];
+            continue;
+        }
+
+        let after_prefix = &rest[start + len..];
+        let resolved = after_prefix.find(delimiter.suffix)
+            .and_then(|end| Some((resolve(&Placeholder::parse(&after_prefix[..end])?)?, end)));
+
+        match resolved {
+            Some((value, end)) => {
+                result.push_str(&rest[..start]);
+                result.push_str(&value);
+                rest = &after_prefix[end + delimiter.suffix.len()..];
+            }
+            None => {
+                // Not a placeholder we know, so the prefix may still open one further along
+                let skip = start + len;
+                result.push_str(&rest[..skip]);
+                rest = &rest[skip..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config::for_tests()
+    }
+
+    fn map(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(key, value)| (String::from(*key), String::from(*value))).collect()
+    }
+
+    // The annotations once the update substitute_annotations returned is patched in
+    fn patched(annotations: &BTreeMap<String, String>, replacements: &BTreeMap<String, String>, config: &Config) -> BTreeMap<String, String> {
+        let mut annotations = annotations.clone();
+        if let Some(update) = substitute_annotations(&annotations, replacements, config).unwrap() {
+            annotations.extend(update.annotations);
+            annotations.retain(|key, _| !update.removed.contains(key));
+        }
+        annotations
+    }
+
+    fn state(annotations: &BTreeMap<String, String>, config: &Config) -> State {
+        parse_state(annotations.get(&config.annotations.state)).unwrap()
+    }
+
+    #[test]
+    fn compute_annotations_first_apply() {
+        let config = config();
+        let current = map(&[("a", "https://$HOST$/"), ("b", "plain")]);
+        let (updated, _, state) = compute_annotations(&current, &map(&[("HOST", "example.com")]), &State::new(), &config);
+
+        assert_eq!(updated, map(&[("a", "https://example.com/")]));
+        assert_eq!(state.len(), 1);
+        assert_eq!(state["a"].template(), "https://$HOST$/");
+    }
+
+    #[test]
+    fn compute_annotations_reapply_is_idempotent() {
+        let config = config();
+        let replacements = map(&[("HOST", "example.com")]);
+        let (updated, _, state) = compute_annotations(&map(&[("a", "https://$HOST$/")]), &replacements, &State::new(), &config);
+
+        let current = map(&[("a", &updated["a"])]);
+        let (updated, _, reapplied_state) = compute_annotations(&current, &replacements, &state, &config);
+        assert!(updated.is_empty());
+        assert_eq!(reapplied_state, state);
+    }
+
+    #[test]
+    fn compute_annotations_value_change() {
+        let config = config();
+        let (updated, _, state) = compute_annotations(&map(&[("a", "https://$HOST$/")]), &map(&[("HOST", "example.com")]), &State::new(), &config);
+
+        let current = map(&[("a", &updated["a"])]);
+        let (updated, _, new_state) = compute_annotations(&current, &map(&[("HOST", "example.org")]), &state, &config);
+        assert_eq!(updated, map(&[("a", "https://example.org/")]));
+        assert_eq!(new_state["a"].template(), "https://$HOST$/");
+    }
+
+    #[test]
+    fn compute_annotations_key_removal() {
+        let config = config();
+        let (updated, _, state) = compute_annotations(&map(&[("a", "https://$HOST$/")]), &map(&[("HOST", "example.com")]), &State::new(), &config);
+
+        // The Secret lost the key, so the annotation goes back to its template and is forgotten
+        let current = map(&[("a", &updated["a"])]);
+        let (updated, _, state) = compute_annotations(&current, &map(&[("PORT", "8443")]), &state, &config);
+        assert_eq!(updated, map(&[("a", "https://$HOST$/")]));
+        assert!(state.is_empty());
+    }
+
+    #[test]
+    fn several_placeholders_in_one_value() {
+        let config = config();
+        let replacements = map(&[("HOST", "example.com"), ("PORT", "8443")]);
+        let updated = substitute_annotations(&map(&[("a", "https://$HOST$:$PORT$/")]), &replacements, &config).unwrap().unwrap().annotations;
+        assert_eq!(updated["a"], "https://example.com:8443/");
+    }
+
+    #[test]
+    fn unchanged_secret_is_a_noop() {
+        let config = config();
+        let replacements = map(&[("HOST", "example.com")]);
+        let annotations = patched(&map(&[("a", "https://$HOST$/")]), &replacements, &config);
+        assert!(substitute_annotations(&annotations, &replacements, &config).unwrap().is_none());
+    }
+
+    #[test]
+    fn literal_edit_of_a_substituted_value_is_kept() {
+        let config = config();
+        let replacements = map(&[("HOST", "example.com")]);
+        let mut annotations = patched(&map(&[("a", "https://$HOST$/")]), &replacements, &config);
+        assert_eq!(annotations["a"], "https://example.com/");
+
+        annotations.insert(String::from("a"), String::from("https://fixed.example/"));
+        let updated = substitute_annotations(&annotations, &replacements, &config).unwrap().unwrap().annotations;
+        assert!(!updated.contains_key("a"));
+
+        let annotations = patched(&annotations, &replacements, &config);
+        assert_eq!(annotations["a"], "https://fixed.example/");
+        assert!(state(&annotations, &config).is_empty());
+    }
+
+    #[test]
+    fn state_shrinks_after_a_placeholder_is_removed() {
+        let config = config();
+        let replacements = map(&[("HOST", "example.com"), ("PORT", "8443")]);
+        let mut annotations = patched(&map(&[("a", "$HOST$"), ("b", "$PORT$")]), &replacements, &config);
+        assert_eq!(state(&annotations, &config).keys().collect::<Vec<_>>(), ["a", "b"]);
+
+        annotations.insert(String::from("b"), String::from("8080"));
+        let annotations = patched(&annotations, &replacements, &config);
+        assert_eq!(annotations["b"], "8080");
+        assert_eq!(state(&annotations, &config).keys().collect::<Vec<_>>(), ["a"]);
+    }
+
+    #[test]
+    fn escapes_without_a_placeholder_are_left_alone() {
+        let config = config();
+        let replacements = map(&[("HOST", "example.com")]);
+        let annotations = map(&[("snippet", "set $$pid 1; return 200 $$;"), ("a", "$$host $HOST$")]);
+
+        let updated = substitute_annotations(&annotations, &replacements, &config).unwrap().unwrap().annotations;
+        assert!(!updated.contains_key("snippet"));
+        assert_eq!(updated["a"], "$host example.com");
+        assert!(!state(&patched(&annotations, &replacements, &config), &config).contains_key("snippet"));
+    }
+
+    #[test]
+    fn state_without_hashes_still_remembers_templates() {
+        let config = config();
+        let replacements = map(&[("HOST", "new.example")]);
+        let annotations = map(&[("a", "old.example"), (config.annotations.state.as_str(), r#"{"a":"$HOST$"}"#)]);
+        assert_eq!(patched(&annotations, &replacements, &config)["a"], "new.example");
+    }
+
+    #[test]
+    fn label_templates_come_from_their_own_annotation() {
+        let config = config();
+        let replacements = map(&[("TIER", "gold")]);
+        let annotations = map(&[(config.annotations.label_templates.as_str(), r#"{"tier":"$TIER$"}"#)]);
+
+        let update = substitute_labels(&BTreeMap::new(), &annotations, &replacements, &config).unwrap().unwrap();
+        assert_eq!(update.labels, map(&[("tier", "gold")]));
+        assert!(compute_annotations(&annotations, &replacements, &State::new(), &config).0.is_empty());
+
+        let mut annotations = annotations.clone();
+        annotations.insert(config.annotations.label_state.clone(), update.state);
+        assert!(substitute_labels(&update.labels, &annotations, &replacements, &config).unwrap().is_none());
+    }
+
+    #[test]
+    fn propagation_respects_protected_and_skipped_annotations() {
+        let mut config = config();
+        config.protected_annotations = vec![String::from("nginx.ingress.kubernetes.io/auth-url")];
+        config.skip_annotation_prefixes = vec![String::from("cert-manager.io/")];
+        let propagate = "HOST=nginx.ingress.kubernetes.io/auth-url,cert-manager.io/issuer,a";
+        let annotations = map(&[(config.annotations.propagate.as_str(), propagate)]);
+
+        let (updated, _, state) = compute_annotations(&annotations, &map(&[("HOST", "example.com")]), &State::new(), &config);
+        assert_eq!(updated, map(&[("a", "example.com")]));
+        assert_eq!(state.keys().collect::<Vec<_>>(), ["a"]);
+    }
+
+    fn propagated(config: &Config) -> BTreeMap<String, String> {
+        map(&[(config.annotations.propagate.as_str(), "HOST=a,b"), ("a", "orig.example")])
+    }
+
+    #[test]
+    fn revert_restores_what_a_propagated_annotation_held() {
+        let config = config();
+        let annotations = patched(&propagated(&config), &map(&[("HOST", "example.com")]), &config);
+        assert_eq!((annotations["a"].as_str(), annotations["b"].as_str()), ("example.com", "example.com"));
+
+        let state = state(&annotations, &config);
+        assert_eq!(state["a"].clone().into_original().as_deref(), Some("orig.example"));
+        assert_eq!(state["b"].clone().into_original(), None);
+    }
+
+    #[test]
+    fn removed_mapping_restores_propagated_annotations() {
+        let config = config();
+        let replacements = map(&[("HOST", "example.com")]);
+        let mut annotations = patched(&propagated(&config), &replacements, &config);
+
+        annotations.remove(&config.annotations.propagate);
+        let annotations = patched(&annotations, &replacements, &config);
+        assert_eq!(annotations["a"], "orig.example");
+        assert!(!annotations.contains_key("b"));
+        assert!(state(&annotations, &config).is_empty());
+    }
+
+    #[test]
+    fn removed_secret_key_restores_propagated_annotations() {
+        let config = config();
+        let annotations = patched(&propagated(&config), &map(&[("HOST", "example.com")]), &config);
+
+        let annotations = patched(&annotations, &map(&[("PORT", "8443")]), &config);
+        assert_eq!(annotations["a"], "orig.example");
+        assert!(!annotations.contains_key("b"));
+        assert!(state(&annotations, &config).is_empty());
+    }
+
+    #[test]
+    fn named_secrets_only_come_from_templatable_annotations() {
+        let mut config = config();
+        config.protected_annotations = vec![String::from("protected")];
+        let annotations = map(&[("protected", "$skipped/KEY$"), ("a", "$used/KEY$")]);
+        assert_eq!(named_secrets(&annotations, &config).into_iter().collect::<Vec<_>>(), ["used"]);
+    }
+}
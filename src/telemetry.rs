@@ -0,0 +1,59 @@
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::trace::{Config, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+// Logs to stdout, plus exports spans over OTLP when OTEL_EXPORTER_OTLP_ENDPOINT is set. RUST_LOG picks the levels,
+// e.g. `info,kube=debug`, defaulting to info
+pub fn init() -> Result<()> {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env()
+        .context("Invalid RUST_LOG")?;
+
+    let fmt = if std::env::var("LOG_FORMAT").is_ok_and(|format| format == "json") {
+        tracing_subscriber::fmt::layer().json().boxed()
+    } else {
+        tracing_subscriber::fmt::layer().boxed()
+    };
+
+    let otlp = match std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(_) => {
+            let provider = tracer_provider()?;
+            let tracer = provider.tracer("annotations-from-secret-applier");
+            opentelemetry::global::set_tracer_provider(provider);
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        Err(_) => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt)
+        .with(otlp)
+        .init();
+    Ok(())
+}
+
+// The exporter picks the endpoint and any other OTEL_EXPORTER_OTLP_* settings up from the environment itself
+fn tracer_provider() -> Result<TracerProvider> {
+    let resource = Resource::new([KeyValue::new("service.name", "annotations-from-secret-applier")]);
+
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_trace_config(Config::default().with_resource(resource))
+        .install_batch(runtime::Tokio)?;
+    Ok(provider)
+}
+
+// Flushes spans still buffered by the batch exporter
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
@@ -0,0 +1,219 @@
+use crate::error::OperatorError;
+use crate::secret_watch::SecretWatch;
+use crate::{get_secret, publish_event, secret_replacements, substitution, OperatorContext, Templated};
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::api::networking::v1::Ingress;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube::api::{Patch, PatchParams};
+use kube::core::{Selector, SelectorExt};
+use kube::runtime::controller::Action;
+use kube::runtime::events::EventType;
+use kube::runtime::reflector::{self, ObjectRef, Store};
+use kube::runtime::{watcher, Controller, WatchStreamExt};
+use kube::{Api, Client, CustomResource, Error, ResourceExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument, warn};
+
+// Declares annotations for every matching Ingress in its namespace, instead of templating each Ingress on its own.
+// Annotations are only ever added or updated, and no state is kept on the Ingress: removing a key or the whole
+// template, or relabelling an Ingress out of the selector, leaves the last rendered values in place
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(group = "kirillorlov.pro", version = "v1alpha1", kind = "AnnotationTemplate", namespaced)]
+#[serde(rename_all = "camelCase")]
+pub struct AnnotationTemplateSpec {
+    // Ingresses in the same namespace the annotations are applied to
+    pub selector: LabelSelector,
+    // Secret in the same namespace providing the placeholder values
+    pub secret_name: String,
+    // Annotation key to template string, using the same placeholder syntax as annotations on the Ingress itself
+    pub annotations: BTreeMap<String, String>,
+}
+
+// Also returns the Ingresses in scope, which reconcile selects from instead of listing them from the API server
+pub fn build_controller(client: &Client, namespace: &Option<String>, watcher_config: &watcher::Config, secret_watch: &SecretWatch) -> (Controller<AnnotationTemplate>, Store<Ingress>) {
+    let (api, ingress_api) = match namespace {
+        Some(namespace) => (
+            Api::<AnnotationTemplate>::namespaced(client.clone(), namespace),
+            Api::<Ingress>::namespaced(client.clone(), namespace),
+        ),
+        None => (Api::all(client.clone()), Api::all(client.clone())),
+    };
+
+    let mut controller = Controller::new(api, watcher::Config::default());
+
+    let store = controller.store();
+    controller = controller.watches_stream(secret_watch.subscribe(), move |secret: Secret| {
+        templates_for(&store, secret.metadata.namespace.as_deref(), |template| template.spec.secret_name == secret.name_any())
+    });
+
+    // A newly created or relabelled Ingress has to pick up the templates already in its namespace that select it
+    let (ingresses, writer) = reflector::store();
+    let ingress_events = reflector::reflector(writer, watcher(ingress_api, watcher_config.clone()))
+        .default_backoff()
+        .applied_objects();
+    let store = controller.store();
+    let controller = controller.watches_stream(ingress_events, move |ingress: Ingress| {
+        templates_for(&store, ingress.metadata.namespace.as_deref(), |template| selects(template, &ingress))
+    });
+
+    (controller, ingresses)
+}
+
+// An invalid selector selects nothing, reconcile reports it
+fn selects(template: &AnnotationTemplate, ingress: &Ingress) -> bool {
+    Selector::try_from(template.spec.selector.clone()).is_ok_and(|selector| selector.matches(ingress.labels()))
+}
+
+fn templates_for<F>(store: &Store<AnnotationTemplate>, namespace: Option<&str>, filter: F) -> Vec<ObjectRef<AnnotationTemplate>>
+where
+    F: Fn(&AnnotationTemplate) -> bool,
+{
+    store.state()
+        .into_iter()
+        .filter(|template| template.metadata.namespace.as_deref() == namespace)
+        .filter(|template| filter(template))
+        .map(|template| ObjectRef::from_obj(template.as_ref()))
+        .collect()
+}
+
+#[instrument(skip_all, fields(
+    resource.kind = "AnnotationTemplate",
+    resource.name = template.metadata.name.as_deref(),
+    resource.namespace = template.metadata.namespace.as_deref(),
+    secret.name = template.spec.secret_name.as_str(),
+))]
+pub async fn reconcile(template: Arc<AnnotationTemplate>, ctx: Arc<OperatorContext>, ingresses: Store<Ingress>) -> Result<Action, OperatorError> {
+    if !*ctx.leader.borrow() || *ctx.paused.borrow() {
+        return Ok(Action::await_change());
+    }
+
+    ctx.metrics.reconciles.inc();
+    let _timer = ctx.metrics.reconcile_duration.start_timer();
+
+    let namespace = match template.metadata.namespace.as_ref() {
+        Some(namespace) => namespace,
+        None => {
+            warn!("Skipping AnnotationTemplate without a namespace");
+            return Ok(Action::await_change());
+        }
+    };
+
+    if !ctx.config.is_namespace_watched(namespace) {
+        warn!("Skipping AnnotationTemplate outside of the watched namespaces");
+        return Ok(Action::await_change());
+    }
+
+    let selector = match Selector::try_from(template.spec.selector.clone()) {
+        Ok(selector) => selector,
+        Err(e) => {
+            error!("Invalid selector in AnnotationTemplate: {}", e);
+            return Ok(Action::await_change());
+        }
+    };
+
+    let secret_key = (namespace.clone(), template.spec.secret_name.clone());
+    let secret = match get_secret(&secret_key, &ctx).await {
+        Ok(secret) => secret,
+        Err(Error::Api(response)) if response.code == 404 => {
+            warn!("Secret {}/{} not found, retrying in {:?}", namespace, template.spec.secret_name, ctx.config.missing_secret_requeue_interval);
+            return Ok(Action::requeue(ctx.config.missing_secret_requeue_interval));
+        }
+        Err(e) => {
+            error!("Failed to get Secret {}/{}: {:?}", namespace, template.spec.secret_name, e);
+            return Err(e.into());
+        }
+    };
+    let replacements = secret_replacements(&secret, &ctx.config);
+
+    // Without the whole cache an Ingress could be missed, it triggers this template again once it shows up anyway
+    if ingresses.wait_until_ready().await.is_err() {
+        return Ok(Action::await_change());
+    }
+
+    let ingress_api = Api::<Ingress>::namespaced(ctx.client.clone(), namespace);
+    let selected = ingresses.state()
+        .into_iter()
+        .filter(|ingress| ingress.metadata.namespace.as_ref() == Some(namespace) && selector.matches(ingress.labels()));
+
+    // One failing Ingress, e.g. on a conflict or a webhook rejecting it, doesn't hold up the others
+    let mut total = 0;
+    let mut failures = Vec::new();
+    for ingress in selected {
+        // The operator-wide selector and IngressClass still win over whatever the template selects
+        if !ctx.config.is_selected(ingress.labels()) || !ingress.is_managed(&ctx.config) {
+            continue;
+        }
+        total += 1;
+        // Already logged by apply
+        if let Err(e) = apply(&ingress_api, &ingress, &template.spec.annotations, &replacements, &ctx).await {
+            ctx.metrics.record_reconcile(namespace, "error");
+            failures.push(e);
+        }
+    }
+
+    match failures.len() {
+        0 => Ok(Action::requeue(ctx.config.requeue_interval)),
+        failed => Err(OperatorError::Partial { failed, total, source: Box::new(failures.swap_remove(0)) }),
+    }
+}
+
+async fn apply(
+    api: &Api<Ingress>,
+    ingress: &Ingress,
+    templates: &BTreeMap<String, String>,
+    replacements: &BTreeMap<String, String>,
+    ctx: &OperatorContext,
+) -> Result<(), OperatorError> {
+    let name = match ingress.metadata.name.clone() {
+        Some(name) => name,
+        None => {
+            warn!("Skipping patch of Ingress without a name");
+            return Ok(());
+        }
+    };
+
+    let updated_annotations = templates.iter()
+        .filter_map(|(key, template)| match substitution::render_complete(template, replacements, &ctx.config) {
+            Some(value) => Some((key, value)),
+            None => {
+                warn!("{} could not be fully substituted, keeping its current value", key);
+                None
+            }
+        })
+        .filter(|(key, value)| ingress.annotations().get(*key) != Some(value))
+        .collect::<BTreeMap<_, _>>();
+
+    if updated_annotations.is_empty() {
+        debug!("Ingress {} already up to date", name);
+        return Ok(());
+    }
+
+    let affected_keys = updated_annotations.keys().map(|key| key.as_str()).collect::<Vec<_>>().join(", ");
+
+    if ctx.config.dry_run {
+        info!(ingress = name.as_str(), annotations = ?updated_annotations, "Dry run, would patch annotations");
+        ctx.metrics.dry_run_patches.inc();
+        return Ok(());
+    }
+
+    let patch = json!({
+        "metadata": {
+            "annotations": updated_annotations,
+        }
+    });
+
+    if let Err(e) = api.patch(&name, &PatchParams::apply(&ctx.config.field_manager), &Patch::Merge(&patch)).await {
+        error!("Failed to patch Ingress {}: {:?}", name, e);
+        return Err(e.into());
+    }
+
+    info!(ingress = name.as_str(), keys = affected_keys.as_str(), "Patched Ingress with templated annotations");
+    ctx.metrics.patches.inc();
+    let note = format!("Applied annotations from AnnotationTemplate: {}", affected_keys);
+    publish_event(&ctx.recorder(ingress), EventType::Normal, "AnnotationsApplied", note).await;
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use base64::Engine;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+type Transform = fn(&str) -> String;
+
+// Everything but the RFC 3986 unreserved characters, so `/`, `?`, `&` and spaces are all encoded
+const URL_ENCODE: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+// Functions usable as `$key|name$`, applied to the resolved value before substitution
+const TRANSFORMS: &[(&str, Transform)] = &[
+    ("base64", |value| base64::engine::general_purpose::STANDARD.encode(value)),
+    ("upper", |value| value.to_uppercase()),
+    ("lower", |value| value.to_lowercase()),
+    ("urlencode", |value| utf8_percent_encode(value, URL_ENCODE).to_string()),
+];
+
+pub fn apply(name: &str, value: &str) -> Option<String> {
+    TRANSFORMS.iter()
+        .find(|(transform_name, _)| *transform_name == name)
+        .map(|(_, transform)| transform(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::substitution::substitute_annotations;
+    use std::collections::BTreeMap;
+
+    fn substituted(template: &str) -> Option<String> {
+        let annotations = BTreeMap::from([(String::from("a"), String::from(template))]);
+        let replacements = BTreeMap::from([(String::from("URL"), String::from("a b/c?d=e"))]);
+        substitute_annotations(&annotations, &replacements, &Config::for_tests()).unwrap()
+            .and_then(|update| update.annotations.get("a").cloned())
+    }
+
+    #[test]
+    fn urlencode_spaces_and_slashes() {
+        assert_eq!(apply("urlencode", "a b/c?d=e").as_deref(), Some("a%20b%2Fc%3Fd%3De"));
+    }
+
+    #[test]
+    fn urlencode_in_a_placeholder() {
+        assert_eq!(substituted("https://example.com/?next=$URL|urlencode$").as_deref(), Some("https://example.com/?next=a%20b%2Fc%3Fd%3De"));
+    }
+
+    #[test]
+    fn unknown_transform_leaves_the_placeholder() {
+        assert_eq!(substituted("$URL|rot13$"), None);
+    }
+}
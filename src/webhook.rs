@@ -0,0 +1,215 @@
+use crate::error::OperatorError;
+use crate::{gateway, substitution, FetchedSecrets, OperatorContext, Templated};
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::post;
+use axum::Router;
+use hyper::server::conn::http1;
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::api::networking::v1::Ingress;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview, Operation};
+use kube::core::{DynamicObject, GroupVersionKind};
+use kube::ResourceExt;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+// Serves `/mutate` over TLS, which the API server requires for webhooks
+pub async fn run(ctx: Arc<OperatorContext>, ready: Arc<AtomicBool>) -> Result<()> {
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config(&ctx.config.tls_cert_file, &ctx.config.tls_key_file)?));
+    let port = ctx.config.webhook_port;
+    let app = Router::new()
+        .route("/mutate", post(mutate))
+        .with_state(ctx);
+
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    info!("Serving the mutating admission webhook on port {}", port);
+    ready.store(true, Ordering::Relaxed);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Failed to accept a webhook connection: {:?}", e);
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("TLS handshake with {} failed: {:?}", peer, e);
+                    return;
+                }
+            };
+            if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                debug!("Webhook connection from {} failed: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+// Read once on startup, so a renewed certificate is only served after a restart
+fn tls_config(cert_file: &Path, key_file: &Path) -> Result<ServerConfig> {
+    let mut certs = BufReader::new(File::open(cert_file).with_context(|| format!("Failed to open TLS_CERT_FILE {:?}", cert_file))?);
+    let certs = rustls_pemfile::certs(&mut certs)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate in {:?}", cert_file))?;
+
+    let mut key = BufReader::new(File::open(key_file).with_context(|| format!("Failed to open TLS_KEY_FILE {:?}", key_file))?);
+    let key = rustls_pemfile::private_key(&mut key)
+        .with_context(|| format!("Invalid private key in {:?}", key_file))?
+        .with_context(|| format!("No private key in {:?}", key_file))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(config)
+}
+
+async fn mutate(State(ctx): State<Arc<OperatorContext>>, body: Bytes) -> Result<impl IntoResponse, StatusCode> {
+    let request = serde_json::from_slice::<AdmissionReview<DynamicObject>>(&body)
+        .map_err(|e| e.to_string())
+        .and_then(|review| TryInto::<AdmissionRequest<DynamicObject>>::try_into(review).map_err(|e| e.to_string()));
+
+    let response = match request {
+        Ok(request) => review(&request, &ctx).await,
+        Err(e) => {
+            warn!("Rejecting malformed AdmissionReview: {}", e);
+            ctx.metrics.admission_reviews.with_label_values(&["invalid"]).inc();
+            AdmissionResponse::invalid(e)
+        }
+    };
+
+    let body = serde_json::to_vec(&response.into_review()).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(([(CONTENT_TYPE, "application/json")], body))
+}
+
+// Never denies anything: when the Secrets can't be read the object is admitted as it is, the same as the controller
+// leaving it alone, so the webhook can't block deployments
+async fn review(request: &AdmissionRequest<DynamicObject>, ctx: &OperatorContext) -> AdmissionResponse {
+    let patch = if is::<Ingress>(&request.kind) {
+        mutation::<Ingress>(request, ctx).await
+    } else if is::<Service>(&request.kind) {
+        mutation::<Service>(request, ctx).await
+    } else if is::<gateway::HttpRoute>(&request.kind) {
+        mutation::<gateway::HttpRoute>(request, ctx).await
+    } else {
+        debug!("Admitting unsupported kind {:?} unchanged", request.kind);
+        Ok(None)
+    };
+
+    let response = AdmissionResponse::from(request);
+    match patch {
+        Ok(Some(patch)) => match response.with_patch(patch) {
+            Ok(response) => {
+                info!(resource.kind = request.kind.kind.as_str(), resource.namespace = request.namespace.as_deref(), resource.name = request.name.as_str(), "Substituted annotations on admission");
+                ctx.metrics.admission_reviews.with_label_values(&["mutated"]).inc();
+                response
+            }
+            Err(e) => {
+                error!("Failed to serialize the admission patch: {:?}", e);
+                ctx.metrics.admission_reviews.with_label_values(&["error"]).inc();
+                AdmissionResponse::from(request)
+            }
+        },
+        Ok(None) => {
+            ctx.metrics.admission_reviews.with_label_values(&["unchanged"]).inc();
+            response
+        }
+        Err(e) => {
+            error!("Failed to substitute annotations of {} {}, admitting it unchanged: {}", request.kind.kind, request.name, e);
+            ctx.metrics.admission_reviews.with_label_values(&["error"]).inc();
+            response
+        }
+    }
+}
+
+fn is<K: Templated>(kind: &GroupVersionKind) -> bool {
+    kind.group == K::group(&()) && kind.kind == K::kind(&())
+}
+
+// The JSON patch substituting the object's annotations, or None to admit it unchanged. Only Secret sourced
+// annotations are handled here, labels, ConfigMaps and spec templates need the controller
+async fn mutation<K: Templated>(request: &AdmissionRequest<DynamicObject>, ctx: &OperatorContext) -> Result<Option<json_patch::Patch>, OperatorError> {
+    // Deletes carry no object, and a dry run must not have side effects such as the Events for missing Secrets
+    let mut object = match &request.object {
+        Some(object) if matches!(request.operation, Operation::Create | Operation::Update) && !request.dry_run => object.clone(),
+        _ => return Ok(None),
+    };
+
+    // Objects being created don't necessarily carry their namespace yet
+    if object.metadata.namespace.is_none() {
+        object.metadata.namespace.clone_from(&request.namespace);
+    }
+    let resource = serde_json::from_value::<K>(serde_json::to_value(object)?)?;
+
+    let namespace = match resource.meta().namespace.as_ref() {
+        Some(namespace) => namespace,
+        None => return Ok(None),
+    };
+    if !ctx.config.is_namespace_watched(namespace) || !ctx.config.is_selected(resource.labels()) || !resource.is_managed(&ctx.config) {
+        return Ok(None);
+    }
+
+    let annotations = resource.annotations();
+    if *ctx.paused.borrow() || annotations.get(&ctx.config.annotations.disabled).is_some_and(|disabled| disabled == "true") {
+        return Ok(None);
+    }
+
+    let secret_references = match crate::secret_name(&resource, &ctx.config) {
+        Some(secret_references) => secret_references,
+        None => return Ok(None),
+    };
+
+    let mut fetched = FetchedSecrets::new();
+    let mut replacements = match crate::fetch_secrets(secret_references, namespace, &resource, ctx, &mut fetched).await? {
+        ControlFlow::Continue(secret_values) => crate::select_secret_keys(secret_values, annotations, &ctx.config),
+        ControlFlow::Break(_) => return Ok(None),
+    };
+
+    for named_secret in substitution::named_secrets(annotations, &ctx.config) {
+        match crate::fetch_secrets(&named_secret, namespace, &resource, ctx, &mut fetched).await? {
+            ControlFlow::Continue(secret_values) => {
+                replacements.extend(secret_values.into_iter().map(|(key, value)| (format!("{}/{}", named_secret, key), value)));
+            }
+            ControlFlow::Break(_) => warn!("Placeholders referencing Secret {} are left unresolved", named_secret),
+        }
+    }
+
+    let update = match substitution::substitute_annotations(annotations, &replacements, &ctx.config)? {
+        Some(update) => update,
+        None => return Ok(None),
+    };
+
+    if ctx.config.dry_run {
+        info!(annotations = ?update.annotations.keys().collect::<Vec<_>>(), "Dry run, would substitute annotations on admission");
+        ctx.metrics.dry_run_patches.inc();
+        return Ok(None);
+    }
+
+    // The API server assigns the resourceVersion itself
+    let meta = ObjectMeta { resource_version: None, ..resource.meta().clone() };
+    let removed = update.removed.iter().map(String::as_str).collect::<Vec<_>>();
+    Ok(Some(crate::json_patch(&meta, &update.annotations, &removed, &BTreeMap::new(), &BTreeMap::new())?))
+}